@@ -0,0 +1,163 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use ffi::{read_master, set_nonblocking};
+use libc;
+use std::fs::File;
+use std::future::{self, Future};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::process::{Command, ExitStatus};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::unix::AsyncFd;
+use tokio::process::Child;
+use {Error, Result, TtyServer};
+
+/// Master TTY registered with the tokio reactor
+///
+/// Must be created from within a tokio runtime. A hangup of the slave (i.e. `EIO`) is read as
+/// an end of file.
+pub struct AsyncPty {
+    // Owned duplicate of the server master
+    master: AsyncFd<File>,
+    server: TtyServer,
+    child: Option<Child>,
+}
+
+impl AsyncPty {
+    /// Register the master TTY of `server`, which is set non-blocking
+    pub fn new(server: TtyServer) -> Result<AsyncPty> {
+        try!(set_nonblocking(server.get_master(), true));
+        let master = try!(server.get_master().try_clone());
+        // The file descriptor is owned by `master`, which is only dropped with the AsyncFd
+        let master = try!(unsafe { AsyncFd::register(master) }.map_err(io::Error::from));
+        Ok(AsyncPty {
+            master: master,
+            server: server,
+            child: None,
+        })
+    }
+
+    /// Spawn a new process connected to the slave TTY (cf. `TtyServer::spawn()`) and register
+    /// the master TTY
+    pub fn spawn(mut server: TtyServer, mut cmd: Command) -> Result<AsyncPty> {
+        try!(server.prepare_command(&mut cmd));
+        let child = try!(::tokio::process::Command::from(cmd).spawn().map_err(Error::Spawn));
+        let mut pty = try!(AsyncPty::new(server));
+        pty.child = Some(child);
+        Ok(pty)
+    }
+
+    /// Get the TTY server, e.g. to resize the TTY or signal the foreground job
+    pub fn server(&self) -> &TtyServer {
+        &self.server
+    }
+
+    /// Get the spawned process, if any
+    pub fn child(&mut self) -> Option<&mut Child> {
+        self.child.as_mut()
+    }
+
+    /// Wait for the spawned process to exit
+    pub fn wait<'a>(&'a mut self) ->
+            Pin<Box<dyn Future<Output = io::Result<ExitStatus>> + Send + 'a>> {
+        match self.child {
+            Some(ref mut child) => Box::pin(child.wait()),
+            None => Box::pin(future::ready(Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                              "No spawned process")))),
+        }
+    }
+}
+
+impl AsyncRead for AsyncPty {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) ->
+            Poll<io::Result<()>> {
+        loop {
+            let mut guard = match self.master.poll_read_ready(cx) {
+                Poll::Ready(Ok(g)) => g,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|master| read_master(master, unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                },
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // Would block
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncPty {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) ->
+            Poll<io::Result<usize>> {
+        loop {
+            let mut guard = match self.master.poll_write_ready(cx) {
+                Poll::Ready(Ok(g)) => g,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let ret = guard.try_io(|master| {
+                match unsafe { libc::write(master.as_raw_fd(), buf.as_ptr() as *const _,
+                                           buf.len()) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    n => Ok(n as usize),
+                }
+            });
+            match ret {
+                Ok(r) => return Poll::Ready(r),
+                // Would block
+                Err(_) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use super::AsyncPty;
+    use tokio::io::AsyncReadExt;
+    use tokio::runtime::Builder;
+    use TtyServer;
+
+    #[test]
+    fn spawn_echo() {
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        // No async block with the 2015 edition
+        let _context = runtime.enter();
+        let server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut cmd = Command::new("echo");
+        cmd.arg("foo");
+        let mut pty = AsyncPty::spawn(server, cmd).unwrap();
+        let mut output = Vec::new();
+        runtime.block_on(pty.read_to_end(&mut output)).unwrap();
+        assert_eq!(output, b"foo\r\n");
+        assert!(runtime.block_on(pty.wait()).unwrap().success());
+    }
+}
@@ -0,0 +1,460 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Fan-out of the master TTY output to multiple peers
+//!
+//! A single thread reads the master TTY and queues its output for each peer, which is written
+//! by a dedicated thread. Only the input peers can write to the master TTY. The master TTY is
+//! read until the last `Broadcast` handle is dropped.
+
+use copy::{Stopper, copy_loop};
+use ffi::read_master;
+use libc;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use termios::{self, Termios, tcsetattr};
+use {Error, FileDesc, Result, RawMode, lock};
+
+const BUFFER_SIZE: usize = 4096;
+
+/// What to do with a peer which doesn't consume the output fast enough
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowPolicy {
+    /// Discard the output which doesn't fit in the peer buffer
+    Drop,
+    /// Wait for the peer, which also stalls the other peers
+    Block,
+    /// Remove the peer
+    Disconnect,
+}
+
+struct QueueState {
+    data: VecDeque<u8>,
+    dropped: usize,
+    closed: bool,
+}
+
+// Bounded output buffer of a peer
+struct Queue {
+    id: usize,
+    capacity: usize,
+    policy: SlowPolicy,
+    state: Mutex<QueueState>,
+    cond: Condvar,
+}
+
+impl Queue {
+    // Return false if the peer must be removed
+    fn push(&self, data: &[u8]) -> bool {
+        let mut state = lock(&self.state);
+        loop {
+            if state.closed {
+                return false;
+            }
+            // A chunk bigger than the whole buffer is queued alone
+            if state.data.is_empty() || state.data.len() + data.len() <= self.capacity {
+                break;
+            }
+            match self.policy {
+                SlowPolicy::Drop => {
+                    state.dropped += data.len();
+                    return true;
+                },
+                SlowPolicy::Block => {
+                    state = match self.cond.wait(state) {
+                        Ok(s) => s,
+                        Err(e) => e.into_inner(),
+                    };
+                },
+                SlowPolicy::Disconnect => {
+                    state.closed = true;
+                    state.data.clear();
+                    self.cond.notify_all();
+                    return false;
+                },
+            }
+        }
+        state.data.extend(data);
+        self.cond.notify_all();
+        true
+    }
+
+    // Return None once closed and empty
+    fn pop(&self) -> Option<Vec<u8>> {
+        let mut state = lock(&self.state);
+        while state.data.is_empty() {
+            if state.closed {
+                return None;
+            }
+            state = match self.cond.wait(state) {
+                Ok(s) => s,
+                Err(e) => e.into_inner(),
+            };
+        }
+        let data = state.data.drain(..).collect();
+        self.cond.notify_all();
+        Some(data)
+    }
+
+    fn close(&self) {
+        lock(&self.state).closed = true;
+        self.cond.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+}
+
+struct Hub {
+    queues: Mutex<Vec<Arc<Queue>>>,
+    next_id: Mutex<usize>,
+}
+
+impl Hub {
+    fn remove(&self, id: usize) {
+        lock(&self.queues).retain(|q| q.id != id);
+    }
+
+    // Disconnect all the peers
+    fn close(&self) {
+        for queue in lock(&self.queues).drain(..) {
+            queue.close();
+        }
+    }
+}
+
+// Reader of the master TTY, shared by the `Broadcast` handles
+struct Source {
+    master: Arc<File>,
+    hub: Arc<Hub>,
+    stopper: Arc<Stopper>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Source {
+    /// Stop reading the master TTY, which is then closed, and disconnect the peers
+    fn drop(&mut self) {
+        self.stopper.stop();
+        // Also wake up the output loop waiting for a blocking peer
+        self.hub.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn output_loop(hub: Arc<Hub>, master: Arc<File>, stopper: Arc<Stopper>) {
+    let mut buf = [0; BUFFER_SIZE];
+    while stopper.wait(master.as_raw_fd(), libc::POLLIN) {
+        let len = match read_master(&*master, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        // Don't hold the lock while blocking on a slow peer
+        let queues = lock(&hub.queues).clone();
+        for queue in queues.iter() {
+            if !queue.push(&buf[..len]) {
+                hub.remove(queue.id);
+            }
+        }
+    }
+    hub.close();
+}
+
+// Write the queued output to the peer, then stop its input loop as well
+fn peer_loop(queue: Arc<Queue>, peer: Arc<FileDesc>, stopper: Arc<Stopper>) {
+    while let Some(data) = queue.pop() {
+        if stopper.write_all(peer.as_raw_fd(), &data).is_err() {
+            queue.close();
+        }
+    }
+    stopper.stop();
+}
+
+fn input_loop(peer: Arc<FileDesc>, master: File, stopper: Arc<Stopper>) {
+    copy_loop(&stopper, peer.as_raw_fd(), master.as_raw_fd(), |_| true);
+}
+
+/// Broadcast the output of a master TTY to multiple peers (cf. `TtyServer::broadcast()`)
+#[derive(Clone)]
+pub struct Broadcast {
+    source: Arc<Source>,
+}
+
+impl Broadcast {
+    /// Start reading `master`, which must not be read by anything else (e.g. a `TtyClient`)
+    ///
+    /// Once the last handle is dropped, `master` is closed and the peers are disconnected.
+    pub fn new(master: File) -> Result<Broadcast> {
+        let stopper = Arc::new(try!(Stopper::new().map_err(Error::Io)));
+        let master = Arc::new(master);
+        let hub = Arc::new(Hub {
+            queues: Mutex::new(Vec::new()),
+            next_id: Mutex::new(0),
+        });
+        let (output_hub, output_master) = (hub.clone(), master.clone());
+        let output_stopper = stopper.clone();
+        let thread = thread::spawn(move || output_loop(output_hub, output_master, output_stopper));
+        Ok(Broadcast {
+            source: Arc::new(Source {
+                master: master,
+                hub: hub,
+                stopper: stopper,
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    /// Add a peer receiving the output, buffered up to `capacity` bytes
+    ///
+    /// If `input` is true, the peer input is forwarded to the master TTY and a peer TTY is put
+    /// in raw mode until the `BroadcastPeer` is dropped. Otherwise, the peer is never read.
+    pub fn add_peer<T>(&self, peer: T, input: bool, capacity: usize, policy: SlowPolicy) ->
+            Result<BroadcastPeer> where T: AsRawFd + IntoRawFd {
+        let stopper = Arc::new(try!(Stopper::new().map_err(Error::Io)));
+        let termios_orig = if input {
+            match Termios::from_fd(peer.as_raw_fd()) {
+                Ok(t) => {
+                    try!(tcsetattr(peer.as_raw_fd(), termios::TCSAFLUSH, &RawMode::default().apply(&t))
+                         .map_err(Error::SetTermios));
+                    Some(t)
+                },
+                // Not a TTY, e.g. a socket
+                Err(_) => None,
+            }
+        } else {
+            None
+        };
+        let master = if input {
+            match self.source.master.try_clone() {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    if let Some(ref t) = termios_orig {
+                        let _ = tcsetattr(peer.as_raw_fd(), termios::TCSAFLUSH, t);
+                    }
+                    return Err(Error::Io(e));
+                },
+            }
+        } else {
+            None
+        };
+        let peer = Arc::new(FileDesc::new(peer.into_raw_fd(), true));
+        let queue = self.add_queue(capacity, policy);
+
+        let (writer_queue, writer_peer) = (queue.clone(), peer.clone());
+        let writer_stopper = stopper.clone();
+        let writer = thread::spawn(move || peer_loop(writer_queue, writer_peer, writer_stopper));
+        let mut threads = vec![writer];
+        if let Some(master) = master {
+            let (input_peer, input_stopper) = (peer.clone(), stopper.clone());
+            threads.push(thread::spawn(move || input_loop(input_peer, master, input_stopper)));
+        }
+
+        Ok(BroadcastPeer {
+            hub: self.source.hub.clone(),
+            queue: queue,
+            peer: peer,
+            termios_orig: termios_orig,
+            stopper: stopper,
+            threads: threads,
+        })
+    }
+
+    /// Receive the output in-process, buffered up to `capacity` bytes (e.g. to filter it)
+    pub fn subscribe(&self, capacity: usize, policy: SlowPolicy) -> Subscriber {
+        Subscriber {
+            hub: self.source.hub.clone(),
+            queue: self.add_queue(capacity, policy),
+        }
+    }
+
+    fn add_queue(&self, capacity: usize, policy: SlowPolicy) -> Arc<Queue> {
+        let id = {
+            let mut next_id = lock(&self.source.hub.next_id);
+            *next_id += 1;
+            *next_id
+        };
+        let queue = Arc::new(Queue {
+            id: id,
+            capacity: capacity,
+            policy: policy,
+            state: Mutex::new(QueueState {
+                data: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+        lock(&self.source.hub.queues).push(queue.clone());
+        queue
+    }
+
+    /// Get the number of connected peers, including the subscribers
+    pub fn peer_count(&self) -> usize {
+        lock(&self.source.hub.queues).len()
+    }
+}
+
+/// In-process receiver of a `Broadcast` output, which is removed when dropped
+pub struct Subscriber {
+    hub: Arc<Hub>,
+    queue: Arc<Queue>,
+}
+
+impl Subscriber {
+    /// Wait for the next output, or return `None` once disconnected (e.g. the master TTY hung up)
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.queue.pop()
+    }
+
+    /// Disconnect from the broadcast, which also wakes up a pending `recv()`
+    pub fn close(&self) {
+        self.hub.remove(self.queue.id);
+        self.queue.close();
+    }
+
+    /// Get the number of output bytes discarded because of the `SlowPolicy::Drop` policy
+    pub fn dropped(&self) -> usize {
+        lock(&self.queue.state).dropped
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Peer of a `Broadcast`, which is removed when dropped
+pub struct BroadcastPeer {
+    hub: Arc<Hub>,
+    queue: Arc<Queue>,
+    peer: Arc<FileDesc>,
+    termios_orig: Option<Termios>,
+    stopper: Arc<Stopper>,
+    // Must be joined before restoring the peer
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BroadcastPeer {
+    /// Get the number of output bytes discarded because of the `SlowPolicy::Drop` policy
+    pub fn dropped(&self) -> usize {
+        lock(&self.queue.state).dropped
+    }
+
+    /// Check if the peer still receives the output
+    pub fn is_connected(&self) -> bool {
+        !self.queue.is_closed()
+    }
+
+    /// Wait until the peer is disconnected, e.g. the master TTY hung up
+    pub fn wait(&self) {
+        let mut state = lock(&self.queue.state);
+        while !state.closed {
+            state = match self.queue.cond.wait(state) {
+                Ok(s) => s,
+                Err(e) => e.into_inner(),
+            };
+        }
+    }
+}
+
+impl Drop for BroadcastPeer {
+    /// Remove the peer and restore its TTY configuration
+    fn drop(&mut self) {
+        self.hub.remove(self.queue.id);
+        self.queue.close();
+        self.stopper.stop();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        if let Some(ref t) = self.termios_orig {
+            let _ = tcsetattr(self.peer.as_raw_fd(), termios::TCSAFLUSH, t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffi::openpty;
+    use libc;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Condvar, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use super::{Broadcast, Queue, QueueState, SlowPolicy};
+
+    fn queue(policy: SlowPolicy) -> Queue {
+        Queue {
+            id: 0,
+            capacity: 4,
+            policy: policy,
+            state: Mutex::new(QueueState {
+                data: VecDeque::new(),
+                dropped: 0,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    #[test]
+    fn slow_policies() {
+        let q = queue(SlowPolicy::Drop);
+        assert!(q.push(b"abc"));
+        assert!(q.push(b"de"));
+        assert_eq!(q.state.lock().unwrap().dropped, 2);
+        assert_eq!(q.pop(), Some(b"abc".to_vec()));
+        // A big chunk still goes through an empty buffer
+        assert!(q.push(b"fghijk"));
+        q.close();
+        assert_eq!(q.pop(), Some(b"fghijk".to_vec()));
+        assert_eq!(q.pop(), None);
+
+        let q = queue(SlowPolicy::Disconnect);
+        assert!(q.push(b"abc"));
+        assert!(!q.push(b"de"));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn dropped_peer() {
+        let tty = openpty(None, None).unwrap();
+        let mut peer = openpty(None, None).unwrap();
+        let broadcast = Broadcast::new(tty.master).unwrap();
+        let p = broadcast.add_peer(peer.slave.try_clone().unwrap(), true, 64, SlowPolicy::Drop)
+            .unwrap();
+        // Let the input loop wait for the peer
+        thread::sleep(Duration::from_millis(100));
+        drop(p);
+        // The input is no longer read once the peer is dropped
+        peer.master.write_all(b"foo\n").unwrap();
+        // Give a leftover reader the time to take it
+        thread::sleep(Duration::from_millis(200));
+        let mut pfd = libc::pollfd { fd: peer.slave.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        assert_eq!(unsafe { libc::poll(&mut pfd, 1, 5000) }, 1);
+        let mut buf = [0; 16];
+        let len = peer.slave.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"foo\n");
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use fd::Pipe;
+use ffi::read_master;
+use libc;
+use std::cmp;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+use FileDesc;
+
+const COPY_BUFFER_SIZE: usize = 4096;
+pub const SPLICE_BUFFER_SIZE: usize = 4096;
+
+pub fn write_all(fd: RawFd, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match unsafe { libc::write(fd, data.as_ptr() as *const _, data.len()) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            },
+            n => data = &data[n as usize..],
+        }
+    }
+    Ok(())
+}
+
+/// Stop flag of the copy loops, which also wakes them up while they wait for a file descriptor
+pub struct Stopper {
+    stopped: AtomicBool,
+    // Readable once stopped
+    event: FileDesc,
+}
+
+impl Stopper {
+    pub fn new() -> io::Result<Stopper> {
+        match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Stopper {
+                stopped: AtomicBool::new(false),
+                event: FileDesc::new(fd, true),
+            }),
+        }
+    }
+
+    // Return false if already stopped
+    pub fn stop(&self) -> bool {
+        if self.stopped.swap(true, SeqCst) {
+            return false;
+        }
+        // Never read, so all the current and future waits return
+        let one: u64 = 1;
+        let len = mem::size_of::<u64>();
+        unsafe { libc::write(self.event.as_raw_fd(), &one as *const _ as *const _, len) };
+        true
+    }
+
+    // Wait for `events` on `fd`, or return false if stopped first
+    pub fn wait(&self, fd: RawFd, events: libc::c_short) -> bool {
+        let mut fds = [
+            libc::pollfd { fd: fd, events: events, revents: 0 },
+            libc::pollfd { fd: self.event.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        loop {
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 &&
+                    io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            // Any other poll error is reported by the following I/O
+            return fds[1].revents == 0;
+        }
+    }
+
+    // Same as `write_all()` but give up once stopped
+    pub fn write_all(&self, fd: RawFd, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if !self.wait(fd, libc::POLLOUT) {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "Copy stopped"));
+            }
+            match unsafe { libc::write(fd, data.as_ptr() as *const _, data.len()) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                },
+                n => data = &data[n as usize..],
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sequence typed on the peer to detach a `TtyClient` from the master TTY
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Escape {
+    sequence: Vec<u8>,
+    line_start: bool,
+}
+
+impl Escape {
+    /// Detach on `key` anywhere in the input, e.g. Ctrl-\ (0x1c) like `dtach(1)`
+    pub fn key(key: u8) -> Escape {
+        Escape {
+            sequence: vec![key],
+            line_start: false,
+        }
+    }
+
+    /// Detach on `sequence` typed at the beginning of a line, e.g. "~." like `ssh(1)`
+    pub fn line_start(sequence: &[u8]) -> Escape {
+        Escape {
+            sequence: sequence.to_vec(),
+            line_start: true,
+        }
+    }
+}
+
+// Withhold the input matching the beginning of the escape sequence
+pub struct EscapeFilter {
+    escape: Escape,
+    matched: usize,
+    line_start: bool,
+}
+
+impl EscapeFilter {
+    pub fn new(escape: Escape) -> EscapeFilter {
+        EscapeFilter {
+            escape: escape,
+            matched: 0,
+            line_start: true,
+        }
+    }
+
+    fn can_match(&self, byte: u8) -> bool {
+        (self.matched != 0 || !self.escape.line_start || self.line_start) &&
+            self.escape.sequence.get(self.matched) == Some(&byte)
+    }
+
+    // Replace `data` with the input to forward, and return true if the escape sequence was typed
+    pub fn filter(&mut self, data: &mut Vec<u8>) -> bool {
+        let input = mem::replace(data, Vec::with_capacity(data.len() + self.matched));
+        for &byte in input.iter() {
+            if !self.can_match(byte) && self.matched != 0 {
+                // Not an escape sequence after all
+                data.extend_from_slice(&self.escape.sequence[..self.matched]);
+                self.matched = 0;
+                self.line_start = false;
+            }
+            if self.can_match(byte) {
+                self.matched += 1;
+                if self.matched == self.escape.sequence.len() {
+                    return true;
+                }
+            } else {
+                data.push(byte);
+                self.line_start = byte == b'\r' || byte == b'\n';
+            }
+        }
+        false
+    }
+}
+
+/// Userspace counterpart of `splice_loop()`, with a `filter` which can rewrite the data before
+/// it is written, or stop the copy by returning false
+///
+/// Return true if the copy was stopped by the filter.
+pub fn copy_loop<F>(stopper: &Stopper, fd_in: RawFd, fd_out: RawFd, mut filter: F) -> bool
+        where F: FnMut(&mut Vec<u8>) -> bool {
+    let src = FileDesc::new(fd_in, false);
+    let mut buf = [0; COPY_BUFFER_SIZE];
+    let mut data = Vec::with_capacity(COPY_BUFFER_SIZE);
+    loop {
+        if !stopper.wait(fd_in, libc::POLLIN) {
+            return false;
+        }
+        // A TTY hangup (i.e. EIO) is an end of file
+        let len = match read_master(&src, &mut buf) {
+            Ok(0) => return false,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        };
+        data.clear();
+        data.extend_from_slice(&buf[..len]);
+        let go_on = filter(&mut data);
+        if stopper.write_all(fd_out, &data).is_err() {
+            return false;
+        }
+        if !go_on {
+            return true;
+        }
+    }
+}
+
+/// How a `TtyClient` forwards the data between the peer and the master TTY
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Use `splice(2)`, or a userspace copy if a file descriptor doesn't support it
+    #[default]
+    Auto,
+    /// Only use `splice(2)`, the binding breaks if a file descriptor doesn't support it
+    Splice,
+    /// Only use a userspace copy, e.g. to compare with `splice(2)`
+    Userspace,
+}
+
+pub enum SpliceEnd {
+    // End of file or I/O error
+    Done,
+    // The rest of the data must go through a userspace copy
+    Unsupported,
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS))
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    match unsafe { libc::splice(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+/// Set the capacity of `pipe` to at least `size` bytes, and return the new capacity
+pub fn set_pipe_size(pipe: &Pipe, size: usize) -> io::Result<usize> {
+    if size > libc::c_int::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Pipe size too big"));
+    }
+    match unsafe { libc::fcntl(pipe.writer.as_raw_fd(), libc::F_SETPIPE_SZ, size as libc::c_int) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+/// Zero-copy transfer from `fd_in` to `fd_out` through `pipe` by chunks of `len` bytes at most,
+/// until `stopper` is stopped
+pub fn splice_loop(stopper: &Stopper, fd_in: RawFd, fd_out: RawFd, pipe: &Pipe, len: usize) ->
+        SpliceEnd {
+    let (pipe_rx, pipe_tx) = (pipe.reader.as_raw_fd(), pipe.writer.as_raw_fd());
+    loop {
+        if !stopper.wait(fd_in, libc::POLLIN) {
+            return SpliceEnd::Done;
+        }
+        let mut pending = match splice(fd_in, pipe_tx, len) {
+            Ok(0) => return SpliceEnd::Done,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if is_unsupported(e) => return SpliceEnd::Unsupported,
+            Err(_) => return SpliceEnd::Done,
+        };
+        while pending != 0 {
+            if !stopper.wait(fd_out, libc::POLLOUT) {
+                return SpliceEnd::Done;
+            }
+            match splice(pipe_rx, fd_out, pending) {
+                Ok(0) => return SpliceEnd::Done,
+                Ok(n) => pending -= n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if is_unsupported(e) => {
+                    // Flush the pipe content
+                    let mut buf = [0; SPLICE_BUFFER_SIZE];
+                    while pending != 0 {
+                        let len = cmp::min(pending, buf.len());
+                        match unsafe { libc::read(pipe_rx, buf.as_mut_ptr() as *mut _, len) } {
+                            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {},
+                            n if n <= 0 => return SpliceEnd::Done,
+                            n => {
+                                if stopper.write_all(fd_out, &buf[..n as usize]).is_err() {
+                                    return SpliceEnd::Done;
+                                }
+                                pending -= n as usize;
+                            },
+                        }
+                    }
+                    return SpliceEnd::Unsupported;
+                },
+                Err(_) => return SpliceEnd::Done,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fd::Pipe;
+    use libc;
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+    use std::thread;
+    use super::{Escape, EscapeFilter, SpliceEnd, Stopper, copy_loop, set_pipe_size, splice_loop};
+
+    fn filter(escape: Escape, chunks: &[&[u8]]) -> (Vec<u8>, bool) {
+        let mut filter = EscapeFilter::new(escape);
+        let mut out = Vec::new();
+        for chunk in chunks.iter() {
+            let mut data = chunk.to_vec();
+            let detached = filter.filter(&mut data);
+            out.extend_from_slice(&data);
+            if detached {
+                return (out, true);
+            }
+        }
+        (out, false)
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(filter(Escape::key(0x1c), &[b"ab\x1ccd"]), (b"ab".to_vec(), true));
+        let ssh = Escape::line_start(b"~.");
+        assert_eq!(filter(ssh.clone(), &[b"~."]), (Vec::new(), true));
+        assert_eq!(filter(ssh.clone(), &[b"ls\r~", b".x"]), (b"ls\r".to_vec(), true));
+        assert_eq!(filter(ssh.clone(), &[b"a~.", b"\n~x"]), (b"a~.\n~x".to_vec(), false));
+        assert_eq!(filter(ssh, &[b"~~."]), (b"~~.".to_vec(), false));
+    }
+
+    #[test]
+    fn splice_fallback() {
+        let stopper = Stopper::new().unwrap();
+        let pipe = Pipe::new().unwrap();
+        let mut input = Pipe::new().unwrap();
+        let output = Pipe::new().unwrap();
+        assert!(set_pipe_size(&pipe, 1 << 16).unwrap() >= 1 << 16);
+        input.writer.write_all(b"foo").unwrap();
+        drop(input.writer);
+        let end = splice_loop(&stopper, input.reader.as_raw_fd(), output.writer.as_raw_fd(), &pipe, 4096);
+        assert!(matches!(end, SpliceEnd::Done));
+
+        // An eventfd doesn't support splice(2)
+        let event = unsafe { libc::eventfd(1, 0) };
+        assert!(event >= 0);
+        let end = splice_loop(&stopper, event, output.writer.as_raw_fd(), &pipe, 4096);
+        unsafe { libc::close(event) };
+        assert!(matches!(end, SpliceEnd::Unsupported));
+    }
+
+    #[test]
+    fn stop_waits() {
+        // Nothing is ever written to the input pipe
+        let input = Pipe::new().unwrap();
+        let output = Pipe::new().unwrap();
+        let stopper = Arc::new(Stopper::new().unwrap());
+        let (fd_in, fd_out) = (input.reader.as_raw_fd(), output.writer.as_raw_fd());
+        let copy_stopper = stopper.clone();
+        let copy = thread::spawn(move || copy_loop(&copy_stopper, fd_in, fd_out, |_| true));
+        let splice_stopper = stopper.clone();
+        let splice = thread::spawn(move || {
+            let pipe = Pipe::new().unwrap();
+            matches!(splice_loop(&splice_stopper, fd_in, fd_out, &pipe, 4096), SpliceEnd::Done)
+        });
+        stopper.stop();
+        assert!(!copy.join().unwrap());
+        assert!(splice.join().unwrap());
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Cause of a TTY failure, with the underlying OS error
+#[derive(Debug)]
+pub enum Error {
+    /// Opening the PTY multiplexer (e.g. `/dev/ptmx`)
+    OpenPtmx { path: PathBuf, cause: io::Error },
+    /// Changing the slave ownership and mode with `grantpt(3)`
+    Grantpt(io::Error),
+    /// Unlocking the slave with `unlockpt(3)`
+    Unlockpt(io::Error),
+    /// Changing the slave ownership or mode requested with `TtyServerBuilder`
+    SlavePermissions(io::Error),
+    /// Getting the slave index with `TIOCGPTN`
+    Ptsname(io::Error),
+    /// Opening the slave, by path or with `TIOCGPTPEER`
+    OpenSlave { path: PathBuf, cause: io::Error },
+    /// Getting the termios of a TTY (e.g. a template or a peer)
+    GetTermios(io::Error),
+    /// Setting the termios of a TTY
+    SetTermios(io::Error),
+    /// Getting or setting a window size
+    Winsize(io::Error),
+    /// Creating or setting up a proxy pipe
+    Pipe(io::Error),
+    /// Spawning a process on the slave
+    Spawn(io::Error),
+    /// Binding or connecting a session socket (cf. `session`)
+    Socket { path: PathBuf, cause: io::Error },
+    /// The slave was already taken by a previous `take_slave()` or `spawn()`
+    SlaveTaken,
+    /// The master is read by a client, which prevents to broadcast its output
+    MasterInUse,
+    /// Any other I/O failure
+    Io(io::Error),
+}
+
+impl Error {
+    /// Get the underlying I/O error, if any
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match *self {
+            Error::OpenPtmx { ref cause, .. } => Some(cause),
+            Error::Grantpt(ref e) => Some(e),
+            Error::Unlockpt(ref e) => Some(e),
+            Error::SlavePermissions(ref e) => Some(e),
+            Error::Ptsname(ref e) => Some(e),
+            Error::OpenSlave { ref cause, .. } => Some(cause),
+            Error::GetTermios(ref e) => Some(e),
+            Error::SetTermios(ref e) => Some(e),
+            Error::Winsize(ref e) => Some(e),
+            Error::Pipe(ref e) => Some(e),
+            Error::Spawn(ref e) => Some(e),
+            Error::Socket { ref cause, .. } => Some(cause),
+            Error::SlaveTaken => None,
+            Error::MasterInUse => None,
+            Error::Io(ref e) => Some(e),
+        }
+    }
+
+    /// Get the underlying errno, if any
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.io_error().and_then(|e| e.raw_os_error())
+    }
+
+    /// Get the path of the file which failed to open, if any
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            Error::OpenPtmx { ref path, .. } => Some(path.as_ref()),
+            Error::OpenSlave { ref path, .. } => Some(path.as_ref()),
+            Error::Socket { ref path, .. } => Some(path.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::OpenPtmx { ref path, ref cause } =>
+                write!(f, "Failed to open the PTY multiplexer {}: {}", path.display(), cause),
+            Error::Grantpt(ref e) => write!(f, "Failed to grant the TTY slave: {}", e),
+            Error::Unlockpt(ref e) => write!(f, "Failed to unlock the TTY slave: {}", e),
+            Error::SlavePermissions(ref e) =>
+                write!(f, "Failed to set the TTY slave ownership or mode: {}", e),
+            Error::Ptsname(ref e) => write!(f, "Failed to get the TTY slave name: {}", e),
+            Error::OpenSlave { ref path, ref cause } =>
+                write!(f, "Failed to open the TTY slave {}: {}", path.display(), cause),
+            Error::GetTermios(ref e) => write!(f, "Failed to get the termios: {}", e),
+            Error::SetTermios(ref e) => write!(f, "Failed to set the termios: {}", e),
+            Error::Winsize(ref e) => write!(f, "Failed to get or set the window size: {}", e),
+            Error::Pipe(ref e) => write!(f, "Failed to set up a pipe: {}", e),
+            Error::Spawn(ref e) => write!(f, "Failed to spawn the process: {}", e),
+            Error::Socket { ref path, ref cause } =>
+                write!(f, "Failed to use the session socket {}: {}", path.display(), cause),
+            Error::SlaveTaken => write!(f, "No TTY slave"),
+            Error::MasterInUse => write!(f, "TTY master already read by a client"),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.io_error() {
+            Some(e) => Some(e),
+            None => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(e) => e,
+            Error::SlaveTaken => io::Error::new(io::ErrorKind::BrokenPipe, err),
+            _ => {
+                let kind = err.io_error().map(|e| e.kind()).unwrap_or(io::ErrorKind::Other);
+                io::Error::new(kind, err)
+            },
+        }
+    }
+}
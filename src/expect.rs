@@ -0,0 +1,242 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Scripted interaction with a program through its TTY, like `expect(1)`
+
+use libc;
+use regex::bytes::Regex;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+use {Error, Result, TtyServer};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Pattern to wait for in the TTY output
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// Exact sequence of bytes
+    Literal(Vec<u8>),
+    /// Regular expression
+    Regex(Regex),
+}
+
+impl Matcher {
+    // Get the start and end of the first match
+    fn find(&self, data: &[u8]) -> Option<(usize, usize)> {
+        match *self {
+            Matcher::Literal(ref lit) => {
+                if lit.is_empty() {
+                    return Some((0, 0));
+                }
+                data.windows(lit.len()).position(|w| w == &lit[..]).map(|i| (i, i + lit.len()))
+            },
+            Matcher::Regex(ref re) => re.find(data).map(|m| (m.start(), m.end())),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Matcher {
+    fn from(lit: &'a str) -> Matcher {
+        Matcher::Literal(lit.as_bytes().to_vec())
+    }
+}
+
+impl<'a> From<&'a [u8]> for Matcher {
+    fn from(lit: &'a [u8]) -> Matcher {
+        Matcher::Literal(lit.to_vec())
+    }
+}
+
+impl From<Regex> for Matcher {
+    fn from(re: Regex) -> Matcher {
+        Matcher::Regex(re)
+    }
+}
+
+/// Result of an `Expect::expect()` call
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The pattern matched, with all the output consumed before the match
+    Match { before: Vec<u8>, matched: Vec<u8> },
+    /// All the slave file descriptors were closed before a match, with the remaining output
+    Eof(Vec<u8>),
+    /// No match before the timeout, the output is kept for the next call
+    Timeout,
+}
+
+/// Get the byte sent by Ctrl-`c` (e.g. 'c' for SIGINT or 'd' for end of file)
+pub fn control_char(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' => Some(c as u8 - b'a' + 1),
+        'A'..='Z' => Some(c as u8 - b'A' + 1),
+        '@' | '[' | '\\' | ']' | '^' | '_' => Some(c as u8 & 0x1f),
+        '?' => Some(0x7f),
+        _ => None,
+    }
+}
+
+/// Drive a program through its TTY
+pub struct Expect {
+    server: TtyServer,
+    child: Option<Child>,
+    buffer: Vec<u8>,
+}
+
+impl Expect {
+    /// Interact with the program already connected to the slave of `server`
+    pub fn new(server: TtyServer) -> Expect {
+        Expect {
+            server: server,
+            child: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Spawn a new process connected to the slave TTY (cf. `TtyServer::spawn()`)
+    pub fn spawn(mut server: TtyServer, cmd: Command) -> Result<Expect> {
+        let child = try!(server.spawn(cmd));
+        let mut expect = Expect::new(server);
+        expect.child = Some(child);
+        Ok(expect)
+    }
+
+    /// Get the TTY server, e.g. to resize the TTY or signal the foreground job
+    pub fn server(&self) -> &TtyServer {
+        &self.server
+    }
+
+    /// Get the spawned process, if any
+    pub fn child(&mut self) -> Option<&mut Child> {
+        self.child.as_mut()
+    }
+
+    /// Get the output read but not consumed yet
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Wait until `pattern` shows up in the TTY output, for at most `timeout` if any
+    pub fn expect<T>(&mut self, pattern: T, timeout: Option<Duration>) -> Result<Outcome>
+            where T: Into<Matcher> {
+        let matcher = pattern.into();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some((start, end)) = matcher.find(&self.buffer) {
+                let matched = self.buffer[start..end].to_vec();
+                let before = self.buffer.drain(..end).take(start).collect();
+                return Ok(Outcome::Match {
+                    before: before,
+                    matched: matched,
+                });
+            }
+            let remaining = match deadline {
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return Ok(Outcome::Timeout);
+                    }
+                    Some(d - now)
+                },
+                None => None,
+            };
+            if !try!(self.wait_output(remaining)) {
+                return Ok(Outcome::Timeout);
+            }
+            let mut buf = [0; READ_BUFFER_SIZE];
+            match try!(self.server.read(&mut buf)) {
+                0 => return Ok(Outcome::Eof(self.buffer.drain(..).collect())),
+                n => self.buffer.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    // Return false on timeout
+    fn wait_output(&self, timeout: Option<Duration>) -> Result<bool> {
+        let ms = match timeout {
+            Some(t) => {
+                let mut ms = t.as_millis();
+                // Round up to not spin on a sub-millisecond timeout
+                if t.subsec_nanos() % 1_000_000 != 0 {
+                    ms += 1;
+                }
+                cmp::min(ms, libc::c_int::MAX as u128) as libc::c_int
+            },
+            None => -1,
+        };
+        let mut fds = libc::pollfd {
+            fd: self.server.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            match unsafe { libc::poll(&mut fds, 1, ms) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(Error::Io(err));
+                    }
+                },
+                0 => return Ok(false),
+                // Also ready on slave hangup
+                _ => return Ok(true),
+            }
+        }
+    }
+
+    /// Send raw input to the program
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.server.write_all(data).map_err(Error::Io)
+    }
+
+    /// Send `line` followed by a new line
+    pub fn send_line(&mut self, line: &str) -> Result<()> {
+        try!(self.send(line.as_bytes()));
+        self.send(b"\n")
+    }
+
+    /// Send Ctrl-`c` (e.g. `send_control('c')`), cf. `control_char()`
+    pub fn send_control(&mut self, c: char) -> Result<()> {
+        match control_char(c) {
+            Some(b) => self.send(&[b]),
+            None => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                 "Not a control character"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::bytes::Regex;
+    use super::{Matcher, control_char};
+
+    #[test]
+    fn control_chars() {
+        assert_eq!(control_char('c'), Some(0x03));
+        assert_eq!(control_char('D'), Some(0x04));
+        assert_eq!(control_char('['), Some(0x1b));
+        assert_eq!(control_char('?'), Some(0x7f));
+        assert_eq!(control_char('1'), None);
+    }
+
+    #[test]
+    fn matchers() {
+        assert_eq!(Matcher::from("word:").find(b"Password: "), Some((4, 9)));
+        assert_eq!(Matcher::from("$ ").find(b"Password: "), None);
+        let re = Regex::new(r"\[y/N\]").unwrap();
+        assert_eq!(Matcher::from(re).find(b"Overwrite [y/N]? "), Some((10, 15)));
+    }
+}
@@ -12,7 +12,7 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use libc::{self, c_int, c_uint, c_ushort};
+use libc::{self, c_int, c_uint, c_ushort, pid_t};
 use std::ffi::CString;
 use std::fs::File;
 use std::io;
@@ -20,30 +20,32 @@ use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
 use termios::{self, Termios, tcsetattr};
+use {Error, Result};
 
 const DEV_PTMX_PATH: &'static str = "/dev/ptmx";
 const DEV_PTS_PATH: &'static str = "/dev/pts";
 
+// The ioctl numbers and open flags differ between architectures (e.g. powerpc, mips or sparc),
+// use the per-target values from libc instead of the asm-generic ones.
 mod raw {
-    use libc::{c_int, c_uint};
+    use libc::c_int;
 
-    // From asm-generic/fcntl.h
-    pub const O_CLOEXEC: c_int = 0o2000000;
+    pub use libc::{O_CLOEXEC, TIOCGPGRP, TIOCGPTN, TIOCGPTPEER, TIOCGSID, TIOCGWINSZ, TIOCPKT,
+                   TIOCSCTTY, TIOCSIG, TIOCSPGRP, TIOCSWINSZ};
+    pub use libc::{grantpt, ioctl, unlockpt};
 
-    // From asm-generic/ioctls.h
-    pub const TIOCGWINSZ: c_int = 0x5413;
-    pub const TIOCSWINSZ: c_int = 0x5414;
-    pub const TIOCGPTN: c_uint = 0x80045430;
-
-    extern {
-        pub fn grantpt(fd: c_int) -> c_int;
-        pub fn ioctl(fd: c_int, req: c_int, ...) -> c_int;
-        pub fn unlockpt(fd: c_int) -> c_int;
-    }
+    // From asm-generic/ioctls.h (same for all architectures)
+    pub const TIOCPKT_FLUSHREAD: c_int = 1;
+    pub const TIOCPKT_FLUSHWRITE: c_int = 2;
+    pub const TIOCPKT_STOP: c_int = 4;
+    pub const TIOCPKT_START: c_int = 8;
+    pub const TIOCPKT_NOSTOP: c_int = 16;
+    pub const TIOCPKT_DOSTOP: c_int = 32;
 }
 
 // From termios.h
 #[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct WinSize {
     ws_row: c_ushort,
     ws_col: c_ushort,
@@ -51,13 +53,45 @@ pub struct WinSize {
     ws_ypixel: c_ushort,
 }
 
+impl WinSize {
+    /// Create a window size of `rows` lines and `cols` columns, without pixel dimensions
+    pub fn new(rows: u16, cols: u16) -> WinSize {
+        WinSize::with_pixels(rows, cols, 0, 0)
+    }
+
+    /// Create a window size with explicit pixel dimensions
+    pub fn with_pixels(rows: u16, cols: u16, xpixel: u16, ypixel: u16) -> WinSize {
+        WinSize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: xpixel,
+            ws_ypixel: ypixel,
+        }
+    }
+
+    /// Number of lines
+    pub fn rows(&self) -> u16 {
+        self.ws_row
+    }
+
+    /// Number of columns
+    pub fn cols(&self) -> u16 {
+        self.ws_col
+    }
+
+    /// Width in pixels (zero if unknown)
+    pub fn xpixel(&self) -> u16 {
+        self.ws_xpixel
+    }
+
+    /// Height in pixels (zero if unknown)
+    pub fn ypixel(&self) -> u16 {
+        self.ws_ypixel
+    }
+}
+
 pub fn get_winsize<T>(slave: &T) -> io::Result<WinSize> where T: AsRawFd {
-    let mut ws = WinSize {
-        ws_row: 0,
-        ws_col: 0,
-        ws_xpixel: 0,
-        ws_ypixel: 0,
-    };
+    let mut ws = WinSize::default();
     match unsafe { raw::ioctl(slave.as_raw_fd(), raw::TIOCGWINSZ, &mut ws) } {
         0 => Ok(ws),
         _ => Err(io::Error::last_os_error()),
@@ -71,14 +105,188 @@ pub fn set_winsize<T>(slave: &T, ws: &WinSize) -> io::Result<()> where T: AsRawF
     }
 }
 
+/// Set or unset the `O_NONBLOCK` flag (e.g. on a master TTY registered with an event loop)
+///
+/// The flag is shared by all the duplicated file descriptors.
+pub fn set_nonblocking<T>(fd: &T, nonblocking: bool) -> io::Result<()> where T: AsRawFd {
+    let flags = match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) } {
+        -1 => return Err(io::Error::last_os_error()),
+        f if nonblocking => f | libc::O_NONBLOCK,
+        f => f & !libc::O_NONBLOCK,
+    };
+    match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Set or unset the `FD_CLOEXEC` flag, which is specific to this file descriptor
+pub fn set_cloexec<T>(fd: &T, cloexec: bool) -> io::Result<()> where T: AsRawFd {
+    let flags = match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) } {
+        -1 => return Err(io::Error::last_os_error()),
+        f if cloexec => f | libc::FD_CLOEXEC,
+        f => f & !libc::FD_CLOEXEC,
+    };
+    match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Read from a `master` TTY, a hangup of the slave (i.e. `EIO`) being a clean end of file
+pub fn read_master<T>(master: &T, buf: &mut [u8]) -> io::Result<usize> where T: AsRawFd {
+    match unsafe { libc::read(master.as_raw_fd(), buf.as_mut_ptr() as *mut _, buf.len()) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // All the slave file descriptors are closed
+                Some(libc::EIO) => Ok(0),
+                _ => Err(err),
+            }
+        },
+        n => Ok(n as usize),
+    }
+}
+
+/// Make `slave` the controlling TTY of the calling process, which must be a session leader
+pub fn set_controlling_tty<T>(slave: &T) -> io::Result<()> where T: AsRawFd {
+    match unsafe { raw::ioctl(slave.as_raw_fd(), raw::TIOCSCTTY, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Get the foreground process group of the TTY
+pub fn get_foreground_pgrp<T>(tty: &T) -> io::Result<pid_t> where T: AsRawFd {
+    let mut pgrp: pid_t = 0;
+    match unsafe { raw::ioctl(tty.as_raw_fd(), raw::TIOCGPGRP, &mut pgrp) } {
+        0 => Ok(pgrp),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Set the foreground process group of the TTY
+///
+/// The calling process must be in the TTY session, otherwise it fails with `ENOTTY`.
+pub fn set_foreground_pgrp<T>(tty: &T, pgrp: pid_t) -> io::Result<()> where T: AsRawFd {
+    match unsafe { raw::ioctl(tty.as_raw_fd(), raw::TIOCSPGRP, &pgrp) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Get the session ID of the TTY (i.e. the PID of its session leader)
+pub fn get_session_id<T>(tty: &T) -> io::Result<pid_t> where T: AsRawFd {
+    let mut sid: pid_t = 0;
+    match unsafe { raw::ioctl(tty.as_raw_fd(), raw::TIOCGSID, &mut sid) } {
+        0 => Ok(sid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Signals the master TTY can send to the foreground process group of the slave
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGINT (e.g. Ctrl-C)
+    INT,
+    /// SIGQUIT (e.g. Ctrl-\\)
+    QUIT,
+    /// SIGTSTP (e.g. Ctrl-Z)
+    TSTP,
+}
+
+impl Signal {
+    /// Get the signal number
+    pub fn as_raw(&self) -> c_int {
+        match *self {
+            Signal::INT => libc::SIGINT,
+            Signal::QUIT => libc::SIGQUIT,
+            Signal::TSTP => libc::SIGTSTP,
+        }
+    }
+}
+
+/// Send `signal` to the foreground process group of the slave TTY, through its `master`
+pub fn send_signal<T>(master: &T, signal: Signal) -> io::Result<()> where T: AsRawFd {
+    match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCSIG, signal.as_raw()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Enable or disable the packet mode on the `master` TTY
+///
+/// In packet mode, each read from the master starts with a control byte (cf. `PacketControl`).
+pub fn set_packet_mode<T>(master: &T, enable: bool) -> io::Result<()> where T: AsRawFd {
+    let mode: c_int = if enable { 1 } else { 0 };
+    match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCPKT, &mode) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Control byte of a packet mode read, telling what happened to the slave output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketControl {
+    bits: u8,
+}
+
+impl PacketControl {
+    pub fn from_bits(bits: u8) -> PacketControl {
+        PacketControl {
+            bits: bits,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    fn contains(&self, flag: c_int) -> bool {
+        self.bits as c_int & flag != 0
+    }
+
+    /// The slave input queue was flushed (i.e. `TIOCPKT_FLUSHREAD`)
+    pub fn flush_read(&self) -> bool {
+        self.contains(raw::TIOCPKT_FLUSHREAD)
+    }
+
+    /// The slave output queue was flushed (i.e. `TIOCPKT_FLUSHWRITE`)
+    pub fn flush_write(&self) -> bool {
+        self.contains(raw::TIOCPKT_FLUSHWRITE)
+    }
+
+    /// The slave output was stopped, e.g. with Ctrl-S (i.e. `TIOCPKT_STOP`)
+    pub fn stop(&self) -> bool {
+        self.contains(raw::TIOCPKT_STOP)
+    }
+
+    /// The slave output was restarted, e.g. with Ctrl-Q (i.e. `TIOCPKT_START`)
+    pub fn start(&self) -> bool {
+        self.contains(raw::TIOCPKT_START)
+    }
+
+    /// The stop and start characters are no longer ^S/^Q (i.e. `TIOCPKT_NOSTOP`)
+    pub fn no_stop(&self) -> bool {
+        self.contains(raw::TIOCPKT_NOSTOP)
+    }
+
+    /// The stop and start characters are back to ^S/^Q (i.e. `TIOCPKT_DOSTOP`)
+    pub fn do_stop(&self) -> bool {
+        self.contains(raw::TIOCPKT_DOSTOP)
+    }
+}
+
 pub struct Pty {
     pub master: File,
     pub slave: File,
     pub path: PathBuf,
 }
 
+const OPEN_NOCTTY_FLAGS: c_int = raw::O_CLOEXEC | libc::O_NOCTTY | libc::O_RDWR;
+
 fn open_noctty<T>(path: &T) -> io::Result<File> where T: AsRef<Path> {
-    let flags = raw::O_CLOEXEC | libc::O_NOCTTY | libc::O_RDWR;
+    let flags = OPEN_NOCTTY_FLAGS;
     // The CString unwrap always succeed on unix
     let cstr = CString::new(path.as_ref().as_os_str().as_bytes()).unwrap();
     match unsafe { libc::open(cstr.as_ptr(), flags, 0) } {
@@ -87,10 +295,107 @@ fn open_noctty<T>(path: &T) -> io::Result<File> where T: AsRef<Path> {
     }
 }
 
+/// PTY creation from a devpts instance
+///
+/// The default factory uses the host `/dev/ptmx` and `/dev/pts`, whereas `PtyFactory::new()`
+/// uses a private devpts instance (i.e. mounted with `newinstance`), which makes its PTYs
+/// invisible from the host `/dev/pts`.
+#[derive(Clone, Debug)]
+pub struct PtyFactory {
+    ptmx: PathBuf,
+    pts: PathBuf,
+}
+
+impl PtyFactory {
+    /// Create PTYs from the devpts instance mounted at `devpts_root` (i.e. `<root>/ptmx`)
+    pub fn new<T>(devpts_root: T) -> PtyFactory where T: AsRef<Path> {
+        let root = devpts_root.as_ref();
+        PtyFactory {
+            ptmx: root.join("ptmx"),
+            pts: root.to_path_buf(),
+        }
+    }
+
+    /// Get the devpts directory where the slaves are
+    pub fn pts_dir(&self) -> &Path {
+        self.pts.as_ref()
+    }
+
+    /// Open a new master TTY (with O_CLOEXEC)
+    pub fn getpt(&self) -> io::Result<File> {
+        open_noctty(&self.ptmx)
+    }
+
+    /// Get the slave TTY path of `master` in this devpts instance
+    pub fn ptsname<T>(&self, master: &mut T) -> io::Result<PathBuf> where T: AsRawFd {
+        Ok(self.pts.join(format!("{}", try!(ptsindex(master)))))
+    }
+
+    /// Open the slave TTY paired with `master`
+    ///
+    /// Use `TIOCGPTPEER` (Linux >= 4.13) to get the slave without going through its path, which
+    /// may not point to the same devpts instance (e.g. in a container). Fallback to open the
+    /// `ptsname` path with older kernels.
+    pub fn open_peer<T>(&self, master: &mut T) -> io::Result<File> where T: AsRawFd {
+        match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCGPTPEER, OPEN_NOCTTY_FLAGS) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    // Unknown ioctl
+                    Some(libc::EINVAL) | Some(libc::ENOTTY) => open_noctty(&try!(self.ptsname(master))),
+                    _ => Err(err),
+                }
+            },
+            fd => Ok(unsafe { File::from_raw_fd(fd) }),
+        }
+    }
+
+    /// Thread-safe (i.e. reentrant) version of `openpty(3)` for this devpts instance
+    pub fn openpty(&self, termp: Option<&Termios>, winp: Option<&WinSize>) -> Result<Pty> {
+        let mut master = try!(self.getpt().map_err(|e| Error::OpenPtmx {
+            path: self.ptmx.clone(),
+            cause: e,
+        }));
+        try!(grantpt(&mut master).map_err(Error::Grantpt));
+        try!(unlockpt(&mut master).map_err(Error::Unlockpt));
+        let name = try!(self.ptsname(&mut master).map_err(Error::Ptsname));
+        let slave = try!(self.open_peer(&mut master).map_err(|e| Error::OpenSlave {
+            path: name.clone(),
+            cause: e,
+        }));
+
+        match termp {
+            Some(t) => try!(tcsetattr(slave.as_raw_fd(), termios::TCSAFLUSH, &t).map_err(Error::SetTermios)),
+            None => {}
+        }
+        match winp {
+            Some(w) => try!(set_winsize(&slave, w).map_err(Error::Winsize)),
+            None => {}
+        }
+
+        // TODO: Add signal handler for SIGWINCH
+        Ok(Pty{
+            master: master,
+            slave: slave,
+            path: name,
+        })
+    }
+}
+
+impl Default for PtyFactory {
+    /// Use the host devpts instance (i.e. `/dev/ptmx` and `/dev/pts`)
+    fn default() -> PtyFactory {
+        PtyFactory {
+            ptmx: PathBuf::from(DEV_PTMX_PATH),
+            pts: PathBuf::from(DEV_PTS_PATH),
+        }
+    }
+}
+
 // Need our own `getpt()` to be able to open with O_CLOEXEC
 #[cfg(target_os = "linux")]
 pub fn getpt() -> io::Result<File> {
-    open_noctty(&DEV_PTMX_PATH)
+    PtyFactory::default().getpt()
 }
 
 pub fn grantpt<T>(master: &mut T) -> io::Result<()> where T: AsRawFd {
@@ -109,37 +414,177 @@ pub fn unlockpt<T>(master: &mut T) -> io::Result<()> where T: AsRawFd {
 
 pub fn ptsindex<T>(master: &mut T) -> io::Result<u32> where T: AsRawFd {
     let mut idx: c_uint = 0;
-    match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCGPTN as c_int, &mut idx) } {
+    match unsafe { raw::ioctl(master.as_raw_fd(), raw::TIOCGPTN, &mut idx) } {
         0 => Ok(idx),
         _ => Err(io::Error::last_os_error()),
     }
 }
 
 pub fn ptsname<T>(master: &mut T) -> io::Result<PathBuf> where T: AsRawFd {
-    Ok(Path::new(DEV_PTS_PATH).join(format!("{}", try!(ptsindex(master)))))
+    PtyFactory::default().ptsname(master)
+}
+
+/// Open the slave TTY paired with `master` from the host devpts instance
+pub fn open_peer<T>(master: &mut T) -> io::Result<File> where T: AsRawFd {
+    PtyFactory::default().open_peer(master)
 }
 
 /// Thread-safe (i.e. reentrant) version of `openpty(3)`
-pub fn openpty(termp: Option<&Termios>, winp: Option<&WinSize>) -> io::Result<Pty> {
-    let mut master = try!(getpt());
-    try!(grantpt(&mut master));
-    try!(unlockpt(&mut master));
-    let name = try!(ptsname(&mut master));
-    let slave = try!(open_noctty(&name));
-
-    match termp {
-        Some(t) => try!(tcsetattr(slave.as_raw_fd(), termios::TCSAFLUSH, &t)),
-        None => {}
-    }
-    match winp {
-        Some(w) => try!(set_winsize(&slave, w)),
-        None => {}
-    }
-
-    // TODO: Add signal handler for SIGWINCH
-    Ok(Pty{
-        master: master,
-        slave: slave,
-        path: name,
-    })
+pub fn openpty(termp: Option<&Termios>, winp: Option<&WinSize>) -> Result<Pty> {
+    PtyFactory::default().openpty(termp, winp)
+}
+
+/// Thread-safe version of `openpty(3)` using the devpts instance mounted at `devpts_root`
+pub fn openpty_in<T>(devpts_root: T, termp: Option<&Termios>, winp: Option<&WinSize>) ->
+        Result<Pty> where T: AsRef<Path> {
+    PtyFactory::new(devpts_root).openpty(termp, winp)
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{Ioctl, c_uint};
+    use std::mem::size_of;
+    use super::{WinSize, raw};
+
+    // From asm-generic/ioctl.h and its powerpc, mips and sparc overrides
+    #[cfg(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips",
+              target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64"))]
+    mod ioc {
+        pub const SIZEBITS: u32 = 13;
+        pub const NONE: u32 = 1;
+        pub const WRITE: u32 = 4;
+        pub const READ: u32 = 2;
+    }
+
+    #[cfg(not(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips",
+                  target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64")))]
+    mod ioc {
+        pub const SIZEBITS: u32 = 14;
+        pub const NONE: u32 = 0;
+        pub const WRITE: u32 = 1;
+        pub const READ: u32 = 2;
+    }
+
+    fn ioc(dir: u32, kind: u8, nr: u8, size: usize) -> Ioctl {
+        assert!(size < (1 << ioc::SIZEBITS));
+        let req = (dir << (16 + ioc::SIZEBITS)) | ((size as u32) << 16) | ((kind as u32) << 8) | nr as u32;
+        req as Ioctl
+    }
+
+    fn io(kind: u8, nr: u8) -> Ioctl {
+        ioc(ioc::NONE, kind, nr, 0)
+    }
+
+    fn ior(kind: u8, nr: u8, size: usize) -> Ioctl {
+        ioc(ioc::READ, kind, nr, size)
+    }
+
+    fn iow(kind: u8, nr: u8, size: usize) -> Ioctl {
+        ioc(ioc::WRITE, kind, nr, size)
+    }
+
+    #[cfg(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips",
+              target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64"))]
+    #[test]
+    fn winsize_ioctls() {
+        assert_eq!(raw::TIOCGWINSZ, ior(b't', 104, size_of::<WinSize>()));
+        assert_eq!(raw::TIOCSWINSZ, iow(b't', 103, size_of::<WinSize>()));
+    }
+
+    // The asm-generic TTY ioctls predate the _IOC encoding
+    #[cfg(not(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips",
+                  target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64")))]
+    #[test]
+    fn winsize_ioctls() {
+        assert_eq!(size_of::<WinSize>(), 8);
+        assert_eq!(raw::TIOCGWINSZ, 0x5413);
+        assert_eq!(raw::TIOCSWINSZ, 0x5414);
+    }
+
+    #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+    #[test]
+    fn pts_ioctls() {
+        assert_eq!(raw::TIOCGPTN, ior(b't', 134, size_of::<c_uint>()));
+        assert_eq!(raw::TIOCGPTPEER, io(b't', 137));
+    }
+
+    #[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+    #[test]
+    fn pts_ioctls() {
+        assert_eq!(raw::TIOCGPTN, ior(b'T', 0x30, size_of::<c_uint>()));
+        assert_eq!(raw::TIOCGPTPEER, io(b'T', 0x41));
+    }
+
+    #[cfg(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips",
+              target_arch = "mips64"))]
+    #[test]
+    fn job_control_ioctls() {
+        use libc::c_int;
+        assert_eq!(raw::TIOCGPGRP, ior(b't', 119, size_of::<c_int>()));
+        assert_eq!(raw::TIOCSPGRP, iow(b't', 118, size_of::<c_int>()));
+    }
+
+    #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+    #[test]
+    fn job_control_ioctls() {
+        use libc::c_int;
+        assert_eq!(raw::TIOCGPGRP, ior(b't', 131, size_of::<c_int>()));
+        assert_eq!(raw::TIOCSPGRP, iow(b't', 130, size_of::<c_int>()));
+        assert_eq!(raw::TIOCSCTTY, io(b't', 132));
+        assert_eq!(raw::TIOCGSID, ior(b't', 133, size_of::<c_int>()));
+    }
+
+    #[cfg(not(any(target_arch = "powerpc", target_arch = "powerpc64", target_arch = "mips",
+                  target_arch = "mips64", target_arch = "sparc", target_arch = "sparc64")))]
+    #[test]
+    fn job_control_ioctls() {
+        assert_eq!(raw::TIOCSCTTY, 0x540E);
+        assert_eq!(raw::TIOCGPGRP, 0x540F);
+        assert_eq!(raw::TIOCSPGRP, 0x5410);
+        assert_eq!(raw::TIOCGSID, 0x5429);
+    }
+
+    #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+    #[test]
+    fn signal_ioctls() {
+        assert_eq!(raw::TIOCSIG, iow(b't', 0x88, size_of::<c_uint>()));
+    }
+
+    #[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+    #[test]
+    fn signal_ioctls() {
+        assert_eq!(raw::TIOCSIG, iow(b'T', 0x36, size_of::<c_uint>()));
+    }
+
+    #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+    #[test]
+    fn packet_ioctls() {
+        assert_eq!(raw::TIOCPKT, iow(b't', 112, size_of::<c_uint>()));
+    }
+
+    #[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+    #[test]
+    fn packet_ioctls() {
+        assert_eq!(raw::TIOCPKT, 0x5470);
+    }
+
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "sparc",
+                  target_arch = "sparc64")))]
+    #[test]
+    fn packet_ioctls() {
+        assert_eq!(raw::TIOCPKT, 0x5420);
+    }
+
+    // Also differs on parisc and alpha, which have no Rust target
+    #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+    #[test]
+    fn open_flags() {
+        assert_eq!(raw::O_CLOEXEC, 0x400000);
+    }
+
+    #[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+    #[test]
+    fn open_flags() {
+        assert_eq!(raw::O_CLOEXEC, 0o2000000);
+    }
 }
@@ -12,82 +12,212 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-#[macro_use]
-extern crate chan;
-
-extern crate chan_signal;
 extern crate fd;
 extern crate libc;
 extern crate termios;
 
-use chan_signal::Signal;
-use fd::{Pipe, set_flags, splice_loop, unset_append_flag};
-use ffi::{get_winsize, openpty, set_winsize};
-use libc::c_int;
-use std::fs::File;
-use std::io;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+#[cfg(feature = "expect")]
+extern crate regex;
+#[cfg(feature = "mio")]
+extern crate mio;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+
+use fd::{Pipe, set_flags, unset_append_flag};
+use ffi::{PtyFactory, WinSize, get_foreground_pgrp, get_session_id, get_winsize};
+use ffi::{send_signal, set_controlling_tty, set_foreground_pgrp, set_nonblocking, set_packet_mode};
+use ffi::{read_master, set_cloexec, set_winsize};
+use broadcast::{Broadcast, BroadcastPeer, SlowPolicy, Subscriber};
+use copy::{EscapeFilter, SPLICE_BUFFER_SIZE, SpliceEnd, Stopper, copy_loop, set_pipe_size};
+use copy::splice_loop;
+use packet::PacketReader;
+use record::Recorder;
+use libc::{c_int, gid_t, mode_t, pid_t, uid_t};
+use std::fs::{File, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
-use termios::{Termios, tcsetattr};
+use std::thread::{self, JoinHandle};
+use termios::{Termios, speed_t, tcflag_t, tcsetattr};
+use winch::{WinchHandle, on_resize};
 
+pub use copy::{CopyMode, Escape};
+pub use error::{Error, Result};
+pub use raw_mode::{RawMode, RestoreTermios};
 pub use fd::FileDesc;
 
+pub mod broadcast;
+mod copy;
+mod error;
 pub mod ffi;
+pub mod packet;
+mod raw_mode;
+pub mod record;
+pub mod replay;
+pub mod session;
+pub mod winch;
+
+#[cfg(feature = "tokio")]
+pub mod async_pty;
+#[cfg(feature = "expect")]
+pub mod expect;
+
+const OBSERVER_BUFFER_SIZE: usize = 1 << 16;
+const CLIENT_BUFFER_SIZE: usize = 1 << 16;
 
 pub struct TtyServer {
     master: File,
     slave: Option<File>,
     path: PathBuf,
+    // Shared with the clones
+    readers: Arc<Mutex<MasterReaders>>,
+}
+
+// Readers of the master TTY output
+#[derive(Default)]
+struct MasterReaders {
+    broadcast: Option<Broadcast>,
+    // Number of clients bound directly to the master TTY
+    clients: usize,
 }
 
 pub struct TtyClient {
+    // Must be unregistered before closing the master and peer file descriptors
+    _winch: Option<WinchHandle>,
     // Need to keep the master file descriptor open
     #[allow(dead_code)]
     master: FileDesc,
     master_status: Option<c_int>,
-    peer: FileDesc,
-    peer_status: Option<c_int>,
-    termios_orig: Termios,
-    do_flush: Arc<AtomicBool>,
+    input: FileDesc,
+    // None if the input is also the output
+    output: Option<FileDesc>,
+    output_status: Option<c_int>,
+    // Peer whose window size is followed
+    winsize_fd: Option<RawFd>,
+    binding: Arc<Binding>,
     flush_event: Receiver<()>,
-    // Automatically send an event when dropped
-    _stop: chan::Sender<()>,
+    // Must be joined before closing the master and peer file descriptors
+    threads: Vec<JoinHandle<()>>,
+    recorder: Option<SharedRecorder>,
+    // Set if bound directly to the master TTY of a TtyServer
+    readers: Option<Arc<Mutex<MasterReaders>>>,
 }
 
 impl TtyServer {
     /// Create a new TTY with the same configuration (termios and size) as the `template` TTY
-    pub fn new<T>(template: Option<&T>) -> io::Result<TtyServer> where T: AsRawFd {
+    pub fn new<T>(template: Option<&T>) -> Result<TtyServer> where T: AsRawFd {
+        TtyServer::new_from(&PtyFactory::default(), template)
+    }
+
+    /// Create a new TTY from the devpts instance mounted at `devpts_root` (e.g. private to a jail)
+    pub fn new_in<T, U>(devpts_root: U, template: Option<&T>) -> Result<TtyServer>
+            where T: AsRawFd, U: AsRef<Path> {
+        TtyServer::new_from(&PtyFactory::new(devpts_root), template)
+    }
+
+    /// Configure a new TTY without a template (e.g. for a headless server)
+    pub fn builder() -> TtyServerBuilder {
+        TtyServerBuilder::new()
+    }
+
+    fn new_from<T>(factory: &PtyFactory, template: Option<&T>) -> Result<TtyServer>
+            where T: AsRawFd {
         // Native runtime does not support RtioTTY::get_winsize()
         let pty = match template {
-            Some(t) => try!(openpty(Some(&try!(Termios::from_fd(t.as_raw_fd()))), Some(&try!(get_winsize(t))))),
-            None => try!(openpty(None, None)),
+            Some(t) => {
+                let termios = try!(Termios::from_fd(t.as_raw_fd()).map_err(Error::GetTermios));
+                let winsize = try!(get_winsize(t).map_err(Error::Winsize));
+                try!(factory.openpty(Some(&termios), Some(&winsize)))
+            },
+            None => try!(factory.openpty(None, None)),
         };
 
         Ok(TtyServer {
             master: pty.master,
             slave: Some(pty.slave),
             path: pty.path,
+            readers: Arc::new(Mutex::new(MasterReaders::default())),
         })
     }
 
     /// Bind the peer TTY with the server TTY
     ///
-    /// The sigwinch_handler must handle the SIGWINCH signal to update the TTY window size.
-    /// This handler can be created with `chan_signal::notify(&[Signal::WINCH])` from the
-    /// chan_signal crate.
+    /// If `handle_resize` is true, the TTY window size follows the peer one (cf. `winch`).
     ///
-    /// Any and all threads spawned must come after the first call to chan_signal::notify!
-    pub fn new_client<T>(&self, peer: T, sigwinch_handler: Option<chan::Receiver<Signal>>) ->
-            io::Result<TtyClient> where T: AsRawFd + IntoRawFd {
-        let master = FileDesc::new(self.master.as_raw_fd(), false);
-        TtyClient::new(master, peer, sigwinch_handler)
+    /// Once the TTY output is broadcast (cf. `broadcast()`), the client receives it from the
+    /// broadcast instead of reading the master TTY, along with the other peers.
+    pub fn new_client<T>(&self, peer: T, handle_resize: bool) -> Result<TtyClient>
+            where T: AsRawFd + IntoRawFd {
+        let mut builder = TtyClient::builder();
+        builder.handle_resize(handle_resize);
+        self.new_client_with(&builder, peer)
+    }
+
+    /// Same as `new_client()` but configured with `builder` (e.g. to record the session)
+    pub fn new_client_with<T>(&self, builder: &TtyClientBuilder, peer: T) -> Result<TtyClient>
+            where T: AsRawFd + IntoRawFd {
+        let peer = FileDesc::new(peer.into_raw_fd(), true);
+        self.bind_client(peer, None, builder.config.clone())
+    }
+
+    /// Same as `new_client_with()` but with distinct peers (cf. `TtyClient::new_split()`)
+    pub fn new_split_client_with<U, V>(&self, builder: &TtyClientBuilder, input: U, output: V) ->
+            Result<TtyClient> where U: IntoRawFd, V: IntoRawFd {
+        let input = FileDesc::new(input.into_raw_fd(), true);
+        let output = FileDesc::new(output.into_raw_fd(), true);
+        self.bind_client(input, Some(output), builder.config.clone())
+    }
+
+    // Bind a client to the broadcast if any, or else directly to the master TTY, which then
+    // prevents to start a broadcast until the client is dropped
+    fn bind_client(&self, input: FileDesc, output: Option<FileDesc>, mut config: ClientConfig) ->
+            Result<TtyClient> {
+        let master = FileDesc::new(try!(self.master.try_clone()).into_raw_fd(), true);
+        let direct = {
+            let mut readers = lock(&self.readers);
+            match readers.broadcast {
+                Some(ref b) => {
+                    config.broadcast = Some(b.clone());
+                    false
+                },
+                None => {
+                    readers.clients += 1;
+                    true
+                },
+            }
+        };
+        match TtyClient::bind_fds(master, input, output, config) {
+            Ok(mut client) => {
+                if direct {
+                    client.readers = Some(self.readers.clone());
+                }
+                Ok(client)
+            },
+            Err(e) => {
+                if direct {
+                    lock(&self.readers).clients -= 1;
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Mirror the TTY output to the `peer` terminal, which is never read nor put in raw mode
+    ///
+    /// The observer is a view-only peer of the TTY broadcast (cf. `broadcast()`), whose output is
+    /// dropped rather than stalling the session if it is too slow. The clients bound afterwards
+    /// with `new_client()` share the same output, but this fails with `Error::MasterInUse` while
+    /// a client bound before is not dropped.
+    pub fn new_observer<T>(&self, peer: T) -> Result<BroadcastPeer> where T: AsRawFd + IntoRawFd {
+        let broadcast = try!(self.broadcast());
+        broadcast.add_peer(peer, false, OBSERVER_BUFFER_SIZE, SlowPolicy::Drop)
     }
 
     /// Get the TTY master file descriptor usable by a `TtyClient`
@@ -95,29 +225,427 @@ impl TtyServer {
         &self.master
     }
 
+    /// Get the current window size of the TTY
+    pub fn winsize(&self) -> Result<WinSize> {
+        get_winsize(&self.master).map_err(Error::Winsize)
+    }
+
+    /// Set the TTY window size to `rows` lines and `cols` columns
+    ///
+    /// The foreground process group of the TTY is notified with a SIGWINCH signal.
+    pub fn resize(&self, rows: u16, cols: u16) -> Result<()> {
+        set_winsize(&self.master, &WinSize::new(rows, cols)).map_err(Error::Winsize)
+    }
+
+    /// Get the process group currently in the foreground of the TTY (e.g. a shell job)
+    pub fn foreground_pgrp(&self) -> Result<pid_t> {
+        get_foreground_pgrp(&self.master).map_err(Error::Io)
+    }
+
+    /// Put the process group `pgrp` in the foreground of the TTY
+    ///
+    /// The calling process must have the slave as controlling terminal. This is never the case
+    /// for a process started with `spawn()`, which leads a new session: this then fails with
+    /// `ENOTTY` and the job control is left to the session (e.g. a shell).
+    ///
+    /// To route a signal to the current job, use `signal()`, or send it to the process group
+    /// returned by `foreground_pgrp()` (i.e. `kill(-pgrp, sig)`).
+    pub fn set_foreground_pgrp(&self, pgrp: pid_t) -> Result<()> {
+        set_foreground_pgrp(&self.master, pgrp).map_err(Error::Io)
+    }
+
+    /// Get the session ID of the TTY (i.e. the PID of the spawned session leader)
+    pub fn session_id(&self) -> Result<pid_t> {
+        get_session_id(&self.master).map_err(Error::Io)
+    }
+
+    /// Send `signal` to the process group in the foreground of the TTY (e.g. the current job)
+    pub fn signal(&self, signal: ffi::Signal) -> Result<()> {
+        send_signal(&self.master, signal).map_err(Error::Io)
+    }
+
+    /// Interrupt the foreground job (i.e. SIGINT)
+    pub fn interrupt(&self) -> Result<()> {
+        self.signal(ffi::Signal::INT)
+    }
+
+    /// Suspend the foreground job (i.e. SIGTSTP)
+    pub fn suspend(&self) -> Result<()> {
+        self.signal(ffi::Signal::TSTP)
+    }
+
+    /// Quit the foreground job (i.e. SIGQUIT)
+    pub fn quit(&self) -> Result<()> {
+        self.signal(ffi::Signal::QUIT)
+    }
+
+    /// Enable or disable the packet mode on the master TTY
+    ///
+    /// Any `TtyClient` bound to this TTY should then be replaced with a `PacketReader`.
+    pub fn set_packet_mode(&self, enable: bool) -> Result<()> {
+        set_packet_mode(&self.master, enable).map_err(Error::Io)
+    }
+
+    /// Get a reader splitting the master TTY output into packets (cf. `set_packet_mode()`)
+    pub fn packet_reader(&self) -> Result<PacketReader<File>> {
+        Ok(PacketReader::new(try!(self.master.try_clone())))
+    }
+
+    /// Set or unset the non-blocking mode of the master TTY (e.g. to register it to an event loop)
+    ///
+    /// Reading the `TtyServer` then fails with `WouldBlock` instead of blocking.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        set_nonblocking(&self.master, nonblocking).map_err(Error::Io)
+    }
+
+    /// Create an independent handle on the same TTY, without its slave
+    ///
+    /// The clone can be moved to another thread, e.g. to read from the TTY while writing to it
+    /// from the original one.
+    pub fn try_clone(&self) -> Result<TtyServer> {
+        Ok(TtyServer {
+            master: try!(self.master.try_clone()),
+            slave: None,
+            path: self.path.clone(),
+            readers: self.readers.clone(),
+        })
+    }
+
+    /// Get independent reader and writer halves of the master TTY
+    pub fn split(&self) -> Result<(TtyReader, TtyWriter)> {
+        let reader = TtyReader {
+            master: try!(self.master.try_clone()),
+        };
+        let writer = TtyWriter {
+            master: try!(self.master.try_clone()),
+        };
+        Ok((reader, writer))
+    }
+
+    /// Get the broadcast of the TTY output to multiple peers, started with the first call
+    ///
+    /// The master TTY is then read by the broadcast, which also feeds the clients bound with
+    /// `new_client()`. It can't be started while such a client reads the master TTY directly
+    /// (i.e. `Error::MasterInUse`). Any other reader of the master TTY (e.g. a `TtyClient` bound
+    /// to `get_master()`) would take a part of the output from the broadcast.
+    pub fn broadcast(&self) -> Result<Broadcast> {
+        let mut readers = lock(&self.readers);
+        if let Some(ref b) = readers.broadcast {
+            return Ok(b.clone());
+        }
+        if readers.clients != 0 {
+            return Err(Error::MasterInUse);
+        }
+        let b = try!(Broadcast::new(try!(self.master.try_clone())));
+        readers.broadcast = Some(b.clone());
+        Ok(b)
+    }
+
     /// Take the TTY slave file descriptor to manually pass it to a process
     pub fn take_slave(&mut self) -> Option<File> {
         self.slave.take()
     }
 
     /// Spawn a new process connected to the slave TTY
-    pub fn spawn(&mut self, mut cmd: Command) -> io::Result<Child> {
+    ///
+    /// The process is the leader of a new session, with the slave TTY as controlling terminal
+    /// and its process group in the foreground.
+    pub fn spawn(&mut self, mut cmd: Command) -> Result<Child> {
+        try!(self.prepare_command(&mut cmd));
+        cmd.spawn().map_err(Error::Spawn)
+    }
+
+    // Connect the command to the slave TTY, which is then closed with `cmd`
+    fn prepare_command(&mut self, cmd: &mut Command) -> Result<()> {
         match self.slave.take() {
             Some(slave) => {
-                // Force new session
-                // TODO: tcsetpgrp
-                cmd.stdin(unsafe { Stdio::from_raw_fd(slave.as_raw_fd()) }).
-                    stdout(unsafe { Stdio::from_raw_fd(slave.as_raw_fd()) }).
+                // Each Stdio owns its FD, which is closed when `cmd` is dropped
+                cmd.stdin(Stdio::from(try!(slave.try_clone()))).
+                    stdout(Stdio::from(try!(slave.try_clone()))).
                     // Must close the slave FD to not wait indefinitely the end of the proxy
-                    stderr(unsafe { Stdio::from_raw_fd(slave.into_raw_fd()) }).
-                    // Don't check the error of setsid because it fails if we're the
-                    // process leader already. We just forked so it shouldn't return
-                    // error, but ignore it anyway.
-                    before_exec(|| { let _ = unsafe { libc::setsid() }; Ok(()) }).
-                    spawn()
+                    stderr(Stdio::from(slave));
+                // Only async-signal-safe calls are allowed in the forked child
+                unsafe {
+                    cmd.pre_exec(|| {
+                        // Force new session: we just forked, so we can't be a process group leader
+                        if libc::setsid() == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        let stdin = FileDesc::new(libc::STDIN_FILENO, false);
+                        try!(set_controlling_tty(&stdin));
+                        if libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpid()) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+                Ok(())
             },
-            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "No TTY slave")),
+            None => Err(Error::SlaveTaken),
+        }
+    }
+}
+
+/// Configuration of a new `TtyServer` without a template TTY (cf. `TtyServer::builder()`)
+///
+/// The termios settings are applied on top of `termios()`, or the kernel defaults.
+#[derive(Clone, Debug)]
+pub struct TtyServerBuilder {
+    factory: PtyFactory,
+    termios: Option<Termios>,
+    echo: Option<bool>,
+    canonical: Option<bool>,
+    erase: Option<u8>,
+    kill: Option<u8>,
+    baud: Option<speed_t>,
+    winsize: Option<WinSize>,
+    nonblocking: bool,
+    cloexec: bool,
+    owner: Option<(uid_t, gid_t)>,
+    mode: Option<mode_t>,
+    keep_slave: bool,
+}
+
+impl TtyServerBuilder {
+    pub fn new() -> TtyServerBuilder {
+        TtyServerBuilder {
+            factory: PtyFactory::default(),
+            termios: None,
+            echo: None,
+            canonical: None,
+            erase: None,
+            kill: None,
+            baud: None,
+            winsize: None,
+            nonblocking: false,
+            cloexec: true,
+            owner: None,
+            mode: None,
+            keep_slave: true,
+        }
+    }
+
+    /// Create the TTY from the devpts instance mounted at `devpts_root` (cf. `TtyServer::new_in()`)
+    pub fn devpts<T>(&mut self, devpts_root: T) -> &mut TtyServerBuilder where T: AsRef<Path> {
+        self.factory = PtyFactory::new(devpts_root);
+        self
+    }
+
+    /// Set the whole slave configuration instead of the kernel defaults
+    pub fn termios(&mut self, termios: Termios) -> &mut TtyServerBuilder {
+        self.termios = Some(termios);
+        self
+    }
+
+    /// Echo the input characters (i.e. `ECHO`)
+    pub fn echo(&mut self, echo: bool) -> &mut TtyServerBuilder {
+        self.echo = Some(echo);
+        self
+    }
+
+    /// Enable the line editing (i.e. `ICANON`)
+    pub fn canonical(&mut self, canonical: bool) -> &mut TtyServerBuilder {
+        self.canonical = Some(canonical);
+        self
+    }
+
+    /// Set the erase character (i.e. `VERASE`)
+    pub fn erase(&mut self, erase: u8) -> &mut TtyServerBuilder {
+        self.erase = Some(erase);
+        self
+    }
+
+    /// Set the kill (i.e. erase line) character (i.e. `VKILL`)
+    pub fn kill(&mut self, kill: u8) -> &mut TtyServerBuilder {
+        self.kill = Some(kill);
+        self
+    }
+
+    /// Set the input and output speeds (e.g. `termios::B38400`)
+    pub fn baud(&mut self, baud: speed_t) -> &mut TtyServerBuilder {
+        self.baud = Some(baud);
+        self
+    }
+
+    /// Set the initial window size
+    pub fn winsize(&mut self, winsize: WinSize) -> &mut TtyServerBuilder {
+        self.winsize = Some(winsize);
+        self
+    }
+
+    /// Open the master TTY with `O_NONBLOCK` (cf. `TtyServer::set_nonblocking()`)
+    pub fn nonblocking(&mut self, nonblocking: bool) -> &mut TtyServerBuilder {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Close the master TTY on `exec(3)` (i.e. `O_CLOEXEC`), which is the default
+    pub fn cloexec(&mut self, cloexec: bool) -> &mut TtyServerBuilder {
+        self.cloexec = cloexec;
+        self
+    }
+
+    /// Change the slave ownership, set to the current user by `grantpt(3)`
+    pub fn slave_owner(&mut self, uid: uid_t, gid: gid_t) -> &mut TtyServerBuilder {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// Change the slave permissions (e.g. `0o600`), set to `0o620` by `grantpt(3)`
+    pub fn slave_mode(&mut self, mode: mode_t) -> &mut TtyServerBuilder {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Keep the slave open for `TtyServer::spawn()` or `take_slave()`, which is the default
+    ///
+    /// Otherwise, the slave must be opened through its path (cf. `TtyServer::as_ref()`). Until
+    /// then, the master TTY is hung up (i.e. reading it fails with `EIO`).
+    pub fn keep_slave(&mut self, keep_slave: bool) -> &mut TtyServerBuilder {
+        self.keep_slave = keep_slave;
+        self
+    }
+
+    fn setup_termios(&self, slave: &File) -> Result<()> {
+        if self.echo.is_none() && self.canonical.is_none() && self.erase.is_none() &&
+                self.kill.is_none() && self.baud.is_none() {
+            return Ok(());
+        }
+        let mut t = try!(Termios::from_fd(slave.as_raw_fd()).map_err(Error::GetTermios));
+        let mut set_flag = |flag: tcflag_t, on: Option<bool>| {
+            match on {
+                Some(true) => t.c_lflag |= flag,
+                Some(false) => t.c_lflag &= !flag,
+                None => {},
+            }
+        };
+        set_flag(termios::ECHO, self.echo);
+        set_flag(termios::ICANON, self.canonical);
+        if let Some(c) = self.erase {
+            t.c_cc[termios::VERASE] = c;
+        }
+        if let Some(c) = self.kill {
+            t.c_cc[termios::VKILL] = c;
+        }
+        if let Some(b) = self.baud {
+            try!(termios::cfsetspeed(&mut t, b).map_err(Error::SetTermios));
         }
+        tcsetattr(slave.as_raw_fd(), termios::TCSANOW, &t).map_err(Error::SetTermios)
+    }
+
+    /// Create the TTY
+    pub fn build(&self) -> Result<TtyServer> {
+        let pty = try!(self.factory.openpty(self.termios.as_ref(), self.winsize.as_ref()));
+        try!(self.setup_termios(&pty.slave));
+        if let Some((uid, gid)) = self.owner {
+            if unsafe { libc::fchown(pty.slave.as_raw_fd(), uid, gid) } == -1 {
+                return Err(Error::SlavePermissions(io::Error::last_os_error()));
+            }
+        }
+        if let Some(mode) = self.mode {
+            try!(pty.slave.set_permissions(Permissions::from_mode(mode))
+                 .map_err(Error::SlavePermissions));
+        }
+        if !self.cloexec {
+            try!(set_cloexec(&pty.master, false).map_err(Error::Io));
+        }
+        if self.nonblocking {
+            try!(set_nonblocking(&pty.master, true).map_err(Error::Io));
+        }
+
+        Ok(TtyServer {
+            master: pty.master,
+            slave: if self.keep_slave { Some(pty.slave) } else { None },
+            path: pty.path,
+            readers: Arc::new(Mutex::new(MasterReaders::default())),
+        })
+    }
+}
+
+impl Default for TtyServerBuilder {
+    fn default() -> TtyServerBuilder {
+        TtyServerBuilder::new()
+    }
+}
+
+/// Register the master TTY, which should be non-blocking (cf. `TtyServer::set_nonblocking()`)
+#[cfg(feature = "mio")]
+impl mio::event::Source for TtyServer {
+    fn register(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) ->
+            io::Result<()> {
+        mio::unix::SourceFd(&self.master.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &mio::Registry, token: mio::Token, interests: mio::Interest) ->
+            io::Result<()> {
+        mio::unix::SourceFd(&self.master.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        mio::unix::SourceFd(&self.master.as_raw_fd()).deregister(registry)
+    }
+}
+
+/// Read the output of the slave TTY, a hangup of the slave being an end of file
+impl Read for TtyServer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_master(&self.master, buf)
+    }
+}
+
+/// Write the input of the slave TTY
+impl Write for TtyServer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+/// Get the master TTY file descriptor
+impl AsRawFd for TtyServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
+
+/// Reading half of a master TTY (cf. `TtyServer::split()`)
+pub struct TtyReader {
+    master: File,
+}
+
+impl Read for TtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_master(&self.master, buf)
+    }
+}
+
+impl AsRawFd for TtyReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
+    }
+}
+
+/// Writing half of a master TTY (cf. `TtyServer::split()`)
+pub struct TtyWriter {
+    master: File,
+}
+
+impl Write for TtyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+impl AsRawFd for TtyWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master.as_raw_fd()
     }
 }
 
@@ -128,124 +656,520 @@ impl AsRef<Path> for TtyServer {
     }
 }
 
-// Ignore errors
-fn copy_winsize<T, U>(src: &T, dst: &U) where T: AsRawFd, U: AsRawFd {
-    if let Ok(ws) = get_winsize(src) {
-        let _ = set_winsize(dst, &ws);
+type SharedRecorder = Arc<Mutex<Box<dyn Recorder>>>;
+
+type SharedTap = Arc<Mutex<Box<dyn FnMut(&[u8]) + Send>>>;
+
+// Ignore the poisoning: a panicking thread (e.g. in a recorder or a tap) must not stop the others
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> where T: ?Sized {
+    match mutex.lock() {
+        Ok(g) => g,
+        Err(e) => e.into_inner(),
+    }
+}
+
+// Copy the peer window size to the master TTY and record it, ignoring errors
+fn resize_from(peer: &FileDesc, master: &FileDesc, recorder: Option<&SharedRecorder>) {
+    if let Ok(ws) = get_winsize(peer) {
+        if set_winsize(master, &ws).is_ok() {
+            if let Some(rec) = recorder {
+                let _ = lock(rec).resize(&ws);
+            }
+        }
+    }
+}
+
+// Original configuration of a TTY input peer
+struct PeerTermios {
+    fd: RawFd,
+    orig: Termios,
+    // None if it must not be restored
+    action: Option<c_int>,
+    // Set while the peer is configured by the binding
+    changed: AtomicBool,
+}
+
+// State shared by a TtyClient and its proxy threads
+struct Binding {
+    stopper: Stopper,
+    // Source of the output instead of the master TTY, if it is broadcast
+    subscriber: Option<Subscriber>,
+    ended: AtomicBool,
+    flush_event: Mutex<Sender<()>>,
+    detached: AtomicBool,
+    termios: Option<PeerTermios>,
+}
+
+impl Binding {
+    // Wake up the proxy threads, and return false if already done
+    fn interrupt(&self) -> bool {
+        if let Some(ref s) = self.subscriber {
+            s.close();
+        }
+        self.stopper.stop()
+    }
+
+    fn end(&self) {
+        self.ended.store(true, Relaxed);
+        let _ = lock(&self.flush_event).send(());
+    }
+
+    // Break the binding, e.g. on end of file, unless it is already broken
+    fn stop(&self) {
+        if self.interrupt() {
+            self.end();
+        }
+    }
+
+    // Break the binding because of the escape sequence, and give back the peer right away
+    fn detach(&self) {
+        if self.interrupt() {
+            self.detached.store(true, Relaxed);
+            self.restore_termios();
+            self.end();
+        }
+    }
+
+    fn restore_termios(&self) {
+        if let Some(ref t) = self.termios {
+            if t.changed.swap(false, Relaxed) {
+                if let Some(action) = t.action {
+                    let _ = tcsetattr(t.fd, action, &t.orig);
+                }
+            }
+        }
+    }
+}
+
+// Options of a TtyClient binding
+#[derive(Clone, Default)]
+struct ClientConfig {
+    handle_resize: bool,
+    recorder: Option<SharedRecorder>,
+    // Set by the first binding with the recorder, which starts the recording
+    recording: Arc<AtomicBool>,
+    record_input: bool,
+    escape: Option<Escape>,
+    copy_mode: CopyMode,
+    raw_mode: RawMode,
+    pipe_size: Option<usize>,
+    input_tap: Option<SharedTap>,
+    output_tap: Option<SharedTap>,
+    restore: RestoreTermios,
+    // Receive the output from this broadcast instead of the master TTY
+    broadcast: Option<Broadcast>,
+}
+
+type Filter = Box<dyn FnMut(&mut Vec<u8>) -> bool + Send>;
+
+// Pipe of a `splice(2)` forward
+struct SplicePipe {
+    pipe: Pipe,
+    // Size of the transferred chunks
+    len: usize,
+    // Fall back to a userspace copy if `splice(2)` is not supported
+    fallback: bool,
+}
+
+// Create the pipe of a forward, if any: there is none for a userspace copy, which is always the
+// case with a filter
+fn forward_pipe(config: &ClientConfig, filtered: bool) -> Result<Option<SplicePipe>> {
+    if config.copy_mode == CopyMode::Userspace || filtered {
+        return Ok(None);
+    }
+    let pipe = try!(Pipe::new().map_err(Error::Pipe));
+    let len = match config.pipe_size {
+        Some(size) => try!(set_pipe_size(&pipe, size).map_err(Error::Pipe)),
+        None => SPLICE_BUFFER_SIZE,
+    };
+    Ok(Some(SplicePipe {
+        pipe: pipe,
+        len: len,
+        fallback: config.copy_mode == CopyMode::Auto,
+    }))
+}
+
+// Forward `fd_in` to `fd_out` through `pipe` (cf. `forward_pipe()`) or with a userspace copy.
+// The end of file of `fd_in` only breaks the binding if `stop_on_end` is true, whereas a filter
+// stopping the copy detaches it.
+fn forward(binding: &Arc<Binding>, fd_in: RawFd, fd_out: RawFd, pipe: Option<SplicePipe>,
+           filter: Option<Filter>, stop_on_end: bool) -> JoinHandle<()> {
+    let binding = binding.clone();
+    thread::spawn(move || {
+        let stopper = &binding.stopper;
+        let unsupported = match pipe {
+            Some(p) => match splice_loop(stopper, fd_in, fd_out, &p.pipe, p.len) {
+                SpliceEnd::Unsupported => p.fallback,
+                SpliceEnd::Done => false,
+            },
+            None => true,
+        };
+        let filtered = if unsupported {
+            let filter = filter.unwrap_or_else(|| Box::new(|_: &mut Vec<u8>| true));
+            copy_loop(stopper, fd_in, fd_out, filter)
+        } else {
+            false
+        };
+        if filtered {
+            binding.detach();
+        } else if stop_on_end {
+            binding.stop();
+        }
+    })
+}
+
+// Forward the master TTY output received by the binding subscriber to `fd_out`
+fn forward_subscribed(binding: &Arc<Binding>, fd_out: RawFd, mut filter: Option<Filter>) ->
+        JoinHandle<()> {
+    let binding = binding.clone();
+    thread::spawn(move || {
+        if let Some(ref subscriber) = binding.subscriber {
+            while let Some(mut data) = subscriber.recv() {
+                if let Some(ref mut filter) = filter {
+                    filter(&mut data);
+                }
+                if binding.stopper.write_all(fd_out, &data).is_err() {
+                    break;
+                }
+            }
+        }
+        binding.stop();
+    })
+}
+
+/// Configuration of a `TtyClient`
+#[derive(Clone, Default)]
+pub struct TtyClientBuilder {
+    config: ClientConfig,
+}
+
+impl TtyClientBuilder {
+    pub fn new() -> TtyClientBuilder {
+        TtyClientBuilder::default()
+    }
+
+    /// Update the master TTY window size according to the peer one (cf. `TtyClient::new()`)
+    pub fn handle_resize(&mut self, handle_resize: bool) -> &mut TtyClientBuilder {
+        self.config.handle_resize = handle_resize;
+        self
+    }
+
+    /// Give the master TTY output to `recorder` (cf. `record`), and the peer input as well if
+    /// `record_input` is true
+    ///
+    /// The recorded data goes through a userspace copy instead of `splice(2)`.
+    /// The recorder is shared by all the clients bound with this builder, the recording being
+    /// started by the first one.
+    pub fn recorder<R>(&mut self, recorder: R, record_input: bool) -> &mut TtyClientBuilder
+            where R: Recorder + 'static {
+        self.config.recorder = Some(Arc::new(Mutex::new(Box::new(recorder))));
+        self.config.recording = Arc::new(AtomicBool::new(false));
+        self.config.record_input = record_input;
+        self
+    }
+
+    /// Detach from the master TTY when `escape` is typed on the peer
+    ///
+    /// `TtyClient::wait()` then returns and `TtyClient::is_detached()` is true, while the
+    /// connected process keeps running. The peer configuration is restored as soon as detached,
+    /// and the master TTY output is no longer forwarded. The peer input goes through a userspace
+    /// copy instead of `splice(2)`.
+    pub fn escape(&mut self, escape: Escape) -> &mut TtyClientBuilder {
+        self.config.escape = Some(escape);
+        self
+    }
+
+    /// Configure the input peer TTY with `mode` instead of `RawMode::default()`
+    ///
+    /// E.g. `RawMode::raw().keep_signals(true)` lets the peer TTY send SIGINT to the local
+    /// process group instead of forwarding Ctrl-C.
+    pub fn raw_mode(&mut self, mode: RawMode) -> &mut TtyClientBuilder {
+        self.config.raw_mode = mode;
+        self
+    }
+
+    /// Restore the input peer TTY according to `restore`, `RestoreTermios::Flush` by default
+    pub fn restore_termios(&mut self, restore: RestoreTermios) -> &mut TtyClientBuilder {
+        self.config.restore = restore;
+        self
+    }
+
+    /// Give all the data forwarded from the peer to the master TTY to `tap`
+    pub fn input_tap<F>(&mut self, tap: F) -> &mut TtyClientBuilder
+            where F: FnMut(&[u8]) + Send + 'static {
+        self.config.input_tap = Some(Arc::new(Mutex::new(Box::new(tap))));
+        self
+    }
+
+    /// Give all the data forwarded from the master TTY to the peer to `tap`
+    pub fn output_tap<F>(&mut self, tap: F) -> &mut TtyClientBuilder
+            where F: FnMut(&[u8]) + Send + 'static {
+        self.config.output_tap = Some(Arc::new(Mutex::new(Box::new(tap))));
+        self
+    }
+
+    /// Set the capacity of the `splice(2)` pipes (i.e. `F_SETPIPE_SZ`), which is also the size of
+    /// the transferred chunks
+    ///
+    /// The binding fails if the kernel refuses this size, e.g. above `/proc/sys/fs/pipe-max-size`
+    /// for an unprivileged process.
+    pub fn pipe_size(&mut self, size: usize) -> &mut TtyClientBuilder {
+        self.config.pipe_size = Some(size);
+        self
+    }
+
+    /// Choose between `splice(2)` and a userspace copy, `CopyMode::Auto` by default
+    ///
+    /// The recorded, tapped or filtered data always goes through a userspace copy.
+    pub fn copy_mode(&mut self, mode: CopyMode) -> &mut TtyClientBuilder {
+        self.config.copy_mode = mode;
+        self
+    }
+
+    /// Setup the peer TTY client and bind it to the master TTY server (cf. `TtyClient::new()`)
+    ///
+    /// The recorder and the taps, if any, are shared by all the clients bound with this builder.
+    /// To bind a `TtyServer`, use `TtyServer::new_client_with()` instead, which takes care of
+    /// its broadcast.
+    pub fn bind<T, U>(&self, master: T, peer: U) -> Result<TtyClient>
+            where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
+        TtyClient::bind(master, peer, self.config.clone())
+    }
+
+    /// Bind distinct input and output peers (cf. `TtyClient::new_split()`)
+    pub fn bind_split<T, U, V>(&self, master: T, input: U, output: V) -> Result<TtyClient>
+            where T: IntoRawFd, U: IntoRawFd, V: IntoRawFd {
+        TtyClient::bind_split(master, input, output, self.config.clone())
     }
 }
 
-// TODO: Handle SIGWINCH to dynamically update WinSize
 // TODO: Replace `spawn` with `scoped` and share variables
 impl TtyClient {
+    /// Get a builder to configure a new `TtyClient`
+    pub fn builder() -> TtyClientBuilder {
+        TtyClientBuilder::new()
+    }
+
     /// Setup the peer TTY client (e.g. stdio) and bind it to the master TTY server
     ///
-    /// The sigwinch_handler must handle the SIGWINCH signal to update the TTY window size.
-    /// This handler can be created with `chan_signal::notify(&[Signal::WINCH])` from the
-    /// chan_signal crate.
-    ///
-    /// Any and all threads spawned must come after the first call to chan_signal::notify!
-    pub fn new<T, U>(master: T, peer: U, sigwinch_handler: Option<chan::Receiver<Signal>>) ->
-            io::Result<TtyClient> where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
-        // Setup peer terminal configuration
-        let termios_orig = try!(Termios::from_fd(peer.as_raw_fd()));
-        let mut termios_peer = try!(Termios::from_fd(peer.as_raw_fd()));
-        termios_peer.c_lflag &= !(termios::ECHO | termios::ICANON | termios::ISIG);
-        termios_peer.c_iflag &= !(termios::IGNBRK | termios::ICRNL);
-        termios_peer.c_iflag |= termios::BRKINT;
-        termios_peer.c_cc[termios::VMIN] = 1;
-        termios_peer.c_cc[termios::VTIME] = 0;
-        // XXX: cfmakeraw
-        try!(tcsetattr(peer.as_raw_fd(), termios::TCSAFLUSH, &termios_peer));
-
-        // Create the proxy
-        let do_flush_main = Arc::new(AtomicBool::new(false));
-        let (event_tx, event_rx): (Sender<()>, Receiver<()>) = channel();
+    /// If `handle_resize` is true, the master TTY window size is updated according to the peer
+    /// one each time the process receives a SIGWINCH (cf. `winch::on_resize()`).
+    pub fn new<T, U>(master: T, peer: U, handle_resize: bool) -> Result<TtyClient>
+            where T: AsRawFd + IntoRawFd, U: AsRawFd + IntoRawFd {
+        let config = ClientConfig {
+            handle_resize: handle_resize,
+            ..ClientConfig::default()
+        };
+        TtyClient::bind(master, peer, config)
+    }
 
-        // Master to peer
-        let (m2p_tx, m2p_rx) = match Pipe::new() {
-            Ok(p) => (p.writer, p.reader),
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+    /// Bind the master TTY to distinct `input` and `output` peers (e.g. a pipe and a socket)
+    ///
+    /// Only the peers which are TTYs are configured: the input is put in raw mode and the window
+    /// size follows the output (or else the input) if `handle_resize` is true.
+    pub fn new_split<T, U, V>(master: T, input: U, output: V, handle_resize: bool) ->
+            Result<TtyClient> where T: IntoRawFd, U: IntoRawFd, V: IntoRawFd {
+        let config = ClientConfig {
+            handle_resize: handle_resize,
+            ..ClientConfig::default()
+        };
+        TtyClient::bind_split(master, input, output, config)
+    }
+
+    fn bind<T, U>(master: T, peer: U, config: ClientConfig) -> Result<TtyClient>
+            where T: IntoRawFd, U: IntoRawFd {
+        let master = FileDesc::new(master.into_raw_fd(), true);
+        let peer = FileDesc::new(peer.into_raw_fd(), true);
+        TtyClient::bind_fds(master, peer, None, config)
+    }
+
+    fn bind_split<T, U, V>(master: T, input: U, output: V, config: ClientConfig) ->
+            Result<TtyClient> where T: IntoRawFd, U: IntoRawFd, V: IntoRawFd {
+        let master = FileDesc::new(master.into_raw_fd(), true);
+        let input = FileDesc::new(input.into_raw_fd(), true);
+        let output = FileDesc::new(output.into_raw_fd(), true);
+        TtyClient::bind_fds(master, input, Some(output), config)
+    }
+
+    // The input is also the output if there is no `output`
+    fn bind_fds(master: FileDesc, input: FileDesc, output: Option<FileDesc>, config: ClientConfig) ->
+            Result<TtyClient> {
+        let (input_fd, output_fd) = match output {
+            Some(ref o) => (input.as_raw_fd(), o.as_raw_fd()),
+            None => (input.as_raw_fd(), input.as_raw_fd()),
+        };
+
+        // Get the peer terminal configuration, if any
+        let termios_orig = match Termios::from_fd(input_fd) {
+            Ok(t) => Some(t),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOTTY) => None,
+            Err(e) => return Err(Error::GetTermios(e)),
         };
-        let do_flush = do_flush_main.clone();
-        let master_fd = master.as_raw_fd();
-        thread::spawn(move || splice_loop(do_flush, None, master_fd, m2p_tx.as_raw_fd()));
+        let winsize_fd = [output_fd, input_fd].iter().cloned()
+            .find(|&fd| unsafe { libc::isatty(fd) } == 1);
+        let recorder = config.recorder.clone();
 
-        let do_flush = do_flush_main.clone();
-        let peer_fd = peer.as_raw_fd();
-        let peer_status = try!(unset_append_flag(peer_fd));
-        thread::spawn(move || splice_loop(do_flush, None, m2p_rx.as_raw_fd(), peer_fd));
+        // Master to peer
+        let output_filter: Option<Filter> = match (recorder.clone(), config.output_tap.clone()) {
+            (None, None) => None,
+            (rec, tap) => Some(Box::new(move |data: &mut Vec<u8>| {
+                if let Some(ref rec) = rec {
+                    let _ = lock(rec).output(data);
+                }
+                if let Some(ref tap) = tap {
+                    (*lock(tap))(data);
+                }
+                true
+            })),
+        };
 
         // Peer to master
-        let (p2m_tx, p2m_rx) = match Pipe::new() {
-            Ok(p) => (p.writer, p.reader),
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+        let rec_input = if config.record_input { recorder.clone() } else { None };
+        let input_filter: Option<Filter> = match (config.escape.clone(), rec_input, config.input_tap.clone()) {
+            (None, None, None) => None,
+            (escape, rec, tap) => {
+                let mut escape = escape.map(EscapeFilter::new);
+                Some(Box::new(move |data: &mut Vec<u8>| {
+                    let escaped = match escape {
+                        Some(ref mut e) => e.filter(data),
+                        None => false,
+                    };
+                    if let Some(ref rec) = rec {
+                        let _ = lock(rec).input(data);
+                    }
+                    if let Some(ref tap) = tap {
+                        (*lock(tap))(data);
+                    }
+                    !escaped
+                }))
+            },
+        };
+
+        // Validate the whole configuration before touching the peer
+        let subscriber = config.broadcast.as_ref()
+            .map(|b| b.subscribe(CLIENT_BUFFER_SIZE, SlowPolicy::Block));
+        let output_pipe = match subscriber {
+            Some(_) => None,
+            None => try!(forward_pipe(&config, output_filter.is_some())),
         };
-        let do_flush = do_flush_main.clone();
-        let peer_fd = peer.as_raw_fd();
-        thread::spawn(move || splice_loop(do_flush, None, peer_fd, p2m_tx.as_raw_fd()));
+        let input_pipe = try!(forward_pipe(&config, input_filter.is_some()));
 
-        let do_flush = do_flush_main.clone();
-        let master_fd = master.as_raw_fd();
-        let master_status = try!(unset_append_flag(master_fd));
-        thread::spawn(move || splice_loop(do_flush, Some(event_tx), p2m_rx.as_raw_fd(), master_fd));
+        let stopper = try!(Stopper::new().map_err(Error::Io));
+
+        let (event_tx, event_rx): (Sender<()>, Receiver<()>) = channel();
+        let action = match config.restore {
+            RestoreTermios::Flush => Some(termios::TCSAFLUSH),
+            RestoreTermios::Drain => Some(termios::TCSADRAIN),
+            RestoreTermios::Now => Some(termios::TCSANOW),
+            RestoreTermios::Never => None,
+        };
+        let binding = Arc::new(Binding {
+            stopper: stopper,
+            subscriber: subscriber,
+            ended: AtomicBool::new(false),
+            flush_event: Mutex::new(event_tx),
+            detached: AtomicBool::new(false),
+            termios: termios_orig.map(|t| PeerTermios {
+                fd: input_fd,
+                orig: t,
+                action: action,
+                changed: AtomicBool::new(false),
+            }),
+        });
+        // Until the proxy is started, dropping the client undoes the setup done so far
+        let mut client = TtyClient {
+            _winch: None,
+            master: master,
+            master_status: None,
+            input: input,
+            output: output,
+            output_status: None,
+            winsize_fd: winsize_fd,
+            binding: binding,
+            flush_event: event_rx,
+            threads: Vec::new(),
+            recorder: recorder,
+            readers: None,
+        };
+        client.output_status = try!(unset_append_flag(output_fd));
+        client.master_status = try!(unset_append_flag(client.master.as_raw_fd()));
 
         // Handle terminal resizing
-        let (stop_tx, stop_rx) = chan::sync(0);
-        if let Some(signal) = sigwinch_handler {
+        if let Some(fd) = winsize_fd.filter(|_| config.handle_resize) {
             // master and peer FD will be close by TtyClient::drop()
-            let master2 = FileDesc::new(master.as_raw_fd(), false);
-            let peer2 = FileDesc::new(peer.as_raw_fd(), false);
-            thread::spawn(move || {
-                'select: loop {
-                    chan_select! {
-                        signal.recv() -> signal => {
-                            if signal != Some(Signal::WINCH) {
-                                continue 'select;
-                            }
-                            copy_winsize(&peer2, &master2);
-                        },
-                        stop_rx.recv() => {
-                            break;
-                        }
-                    }
-                }
-            });
+            let master2 = FileDesc::new(client.master.as_raw_fd(), false);
+            let peer2 = FileDesc::new(fd, false);
+            let rec = client.recorder.clone();
+            client._winch = Some(try!(on_resize(move || resize_from(&peer2, &master2, rec.as_ref()))));
         }
 
-        Ok(TtyClient {
-            master: FileDesc::new(master.into_raw_fd(), true),
-            master_status: master_status,
-            peer: FileDesc::new(peer.into_raw_fd(), true),
-            peer_status: peer_status,
-            termios_orig: termios_orig,
-            do_flush: do_flush_main,
-            flush_event: event_rx,
-            _stop: stop_tx,
-        })
+        // Setup peer terminal configuration, restored by TtyClient::drop()
+        if let Some(ref t) = client.binding.termios {
+            try!(tcsetattr(input_fd, termios::TCSAFLUSH, &config.raw_mode.apply(&t.orig))
+                 .map_err(Error::SetTermios));
+            t.changed.store(true, Relaxed);
+        }
+
+        if let Some(ref rec) = client.recorder {
+            // Already started if shared with a previous client
+            if !config.recording.swap(true, Relaxed) {
+                let winsize = winsize_fd.and_then(|fd| get_winsize(&FileDesc::new(fd, false)).ok())
+                    .or_else(|| get_winsize(&client.master).ok()).unwrap_or_default();
+                let _ = lock(rec).start(&winsize);
+            }
+        }
+
+        // Create the proxy, which can't fail anymore
+        let master_fd = client.master.as_raw_fd();
+        let output = match client.binding.subscriber {
+            Some(_) => forward_subscribed(&client.binding, output_fd, output_filter),
+            None => forward(&client.binding, master_fd, output_fd, output_pipe, output_filter, true),
+        };
+        // The end of a non-TTY input (e.g. a pipe) doesn't stop the output
+        let input = forward(&client.binding, input_fd, master_fd, input_pipe, input_filter,
+                            termios_orig.is_some());
+        client.threads = vec![output, input];
+        Ok(client)
     }
 
     /// Wait until the TTY binding broke (e.g. the connected process exited)
     pub fn wait(&self) {
-        while !self.do_flush.load(Relaxed) {
+        while !self.binding.ended.load(Relaxed) {
             let _ = self.flush_event.recv();
         }
     }
 
+    /// Check if the binding ended because of the escape sequence (cf. `TtyClientBuilder::escape()`)
+    pub fn is_detached(&self) -> bool {
+        self.binding.detached.load(Relaxed)
+    }
+
     /// Update the terminal window size according to the peer
+    ///
+    /// Nothing is done if no peer is a TTY.
     pub fn update_winsize(&mut self) {
-        copy_winsize(&self.peer, &self.master);
+        if let Some(fd) = self.winsize_fd {
+            resize_from(&FileDesc::new(fd, false), &self.master, self.recorder.as_ref());
+        }
     }
 }
 
 impl Drop for TtyClient {
     /// Cleanup the peer TTY
     fn drop(&mut self) {
-        self.do_flush.store(true, Relaxed);
-        let _ = tcsetattr(self.peer.as_raw_fd(), termios::TCSAFLUSH, &self.termios_orig);
+        // The proxy threads must not use the file descriptors once closed
+        self.binding.interrupt();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        if let Some(ref readers) = self.readers {
+            lock(readers).clients -= 1;
+        }
+        self.binding.restore_termios();
 
         // Restore the append flag if needed
-        let tty_fd = [(&self.peer, self.peer_status), (&self.master, self.master_status)];
+        let output = self.output.as_ref().unwrap_or(&self.input);
+        let tty_fd = [(output, self.output_status), (&self.master, self.master_status)];
         for &(fd, status) in tty_fd.iter() {
             if let Some(s) = status {
                 let _ = set_flags(fd.as_raw_fd(), s);
@@ -253,3 +1177,265 @@ impl Drop for TtyClient {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fd::Pipe;
+    use ffi::{WinSize, get_winsize, openpty, set_nonblocking};
+    use libc;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Duration;
+    use record::Recorder;
+    use super::{Error, Escape, TtyClient, TtyServer};
+    use termios::{self, Termios};
+
+    #[test]
+    fn controlling_tty() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut cmd = Command::new("sh");
+        // Opening /dev/tty fails without a controlling TTY
+        cmd.args(["-c", "exec 3</dev/tty && echo ctty"]);
+        let mut child = server.spawn(cmd).unwrap();
+        let mut output = String::new();
+        server.read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, "ctty\r\n");
+    }
+
+    #[test]
+    fn spawn_failure() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut child = server.spawn(Command::new("cat")).unwrap();
+        // TIOCSCTTY fails on a TTY which is already the controlling one of another session
+        let slave = OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY)
+            .open(&server.path).unwrap();
+        server.slave = Some(slave);
+        match server.spawn(Command::new("true")) {
+            Err(Error::Spawn(e)) => assert_eq!(e.raw_os_error(), Some(libc::EPERM)),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    // Read `fd` until `expected` is found, or give up after a few seconds of inactivity
+    fn read_until<T>(fd: &mut T, expected: &[u8]) -> Option<Vec<u8>> where T: AsRawFd + Read {
+        let mut data = Vec::new();
+        let mut buf = [0; 1024];
+        while !data.windows(expected.len()).any(|w| w == expected) {
+            let mut pfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut pfd, 1, 5000) } != 1 {
+                return None;
+            }
+            match fd.read(&mut buf) {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        Some(data)
+    }
+
+    #[test]
+    fn escape_detach() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut child = server.spawn(Command::new("cat")).unwrap();
+        let mut peer = openpty(None, None).unwrap();
+        let orig = Termios::from_fd(peer.slave.as_raw_fd()).unwrap();
+        let mut builder = TtyClient::builder();
+        builder.escape(Escape::key(0x1c));
+        let client = server.new_client_with(&builder, peer.slave.try_clone().unwrap()).unwrap();
+        assert!(Termios::from_fd(peer.slave.as_raw_fd()).unwrap() != orig);
+        peer.master.write_all(b"\x1c").unwrap();
+        client.wait();
+        assert!(client.is_detached());
+        // The peer is given back without waiting for the client to be dropped
+        assert_eq!(Termios::from_fd(peer.slave.as_raw_fd()).unwrap(), orig);
+
+        // The client doesn't read the master anymore
+        server.write_all(b"foo\n").unwrap();
+        assert!(read_until(&mut server, b"foo\r\nfoo\r\n").is_some());
+        drop(client);
+        set_nonblocking(&peer.master, true).unwrap();
+        let err = peer.master.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn pipe_peers() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "read x && echo got:$x"]);
+        let mut child = server.spawn(cmd).unwrap();
+        let (input, mut output) = (Pipe::new().unwrap(), Pipe::new().unwrap());
+        let client = TtyClient::new_split(server.get_master().try_clone().unwrap(), input.reader,
+                                          output.writer, true).unwrap();
+        // The end of the input doesn't stop the output
+        let mut writer = input.writer;
+        writer.write_all(b"foo\n").unwrap();
+        drop(writer);
+        assert!(read_until(&mut output.reader, b"got:foo\r\n").is_some());
+        client.wait();
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn invalid_pipe_size() {
+        let server = TtyServer::new::<TtyServer>(None).unwrap();
+        let peer = openpty(None, None).unwrap();
+        let orig = Termios::from_fd(peer.slave.as_raw_fd()).unwrap();
+        let mut builder = TtyClient::builder();
+        builder.pipe_size(usize::MAX);
+        match builder.bind(server.get_master().try_clone().unwrap(), peer.slave.try_clone().unwrap()) {
+            Err(Error::Pipe(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        // The peer is left untouched
+        assert_eq!(Termios::from_fd(peer.slave.as_raw_fd()).unwrap(), orig);
+    }
+
+    #[test]
+    fn observer_and_client() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "read x && seq 1 3000"]);
+        let mut child = server.spawn(cmd).unwrap();
+        let mut view = openpty(None, None).unwrap();
+        let _observer = server.new_observer(view.slave.try_clone().unwrap()).unwrap();
+        let mut peer = openpty(None, None).unwrap();
+        let _client = server.new_client(peer.slave.try_clone().unwrap(), false).unwrap();
+        let observed = thread::spawn(move || read_until(&mut view.master, b"3000\r"));
+        peer.master.write_all(b"go\r").unwrap();
+        // The peers may process the output differently
+        let lines = |data: Vec<u8>| data.into_iter().filter(|&c| c != b'\r').collect::<Vec<_>>();
+        let output = lines(read_until(&mut peer.master, b"3000\r").unwrap());
+        assert!(output.windows(7).any(|w| w == b"\n1500\n1"));
+        assert_eq!(lines(observed.join().unwrap().unwrap()), output);
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn broadcast_hangup() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut child = server.spawn(Command::new("cat")).unwrap();
+        let view = openpty(None, None).unwrap();
+        let observer = server.new_observer(view.slave.try_clone().unwrap()).unwrap();
+        drop(observer);
+        drop(server);
+        for _ in 0..50 {
+            if let Some(status) = child.try_wait().unwrap() {
+                assert_eq!(status.signal(), Some(libc::SIGHUP));
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+        panic!("No hangup");
+    }
+
+    #[test]
+    fn master_in_use() {
+        let server = TtyServer::new::<TtyServer>(None).unwrap();
+        let peer = openpty(None, None).unwrap();
+        let view = openpty(None, None).unwrap();
+        let builder = TtyClient::builder();
+        let client = server.new_client_with(&builder, peer.slave.try_clone().unwrap()).unwrap();
+        match server.new_observer(view.slave.try_clone().unwrap()) {
+            Err(Error::MasterInUse) => {},
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        drop(client);
+        let _observer = server.new_observer(view.slave.try_clone().unwrap()).unwrap();
+        let _client = server.new_client(peer.slave.try_clone().unwrap(), false).unwrap();
+    }
+
+    struct Starts(Arc<AtomicUsize>);
+
+    impl Recorder for Starts {
+        fn start(&mut self, _: &WinSize) -> io::Result<()> {
+            self.0.fetch_add(1, Relaxed);
+            Ok(())
+        }
+
+        fn output(&mut self, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn shared_recorder() {
+        let server = TtyServer::new::<TtyServer>(None).unwrap();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let mut builder = TtyClient::builder();
+        builder.recorder(Starts(starts.clone()), false);
+        let peers = [openpty(None, None).unwrap(), openpty(None, None).unwrap()];
+        let clients = peers.iter().map(|p| {
+            builder.bind(server.get_master().try_clone().unwrap(), p.slave.try_clone().unwrap())
+                .unwrap()
+        }).collect::<Vec<_>>();
+        assert_eq!(starts.load(Relaxed), 1);
+        drop(clients);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut child = server.spawn(Command::new("cat")).unwrap();
+        server.interrupt().unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGINT));
+    }
+
+    #[test]
+    fn job_control() {
+        let mut server = TtyServer::new::<TtyServer>(None).unwrap();
+        let mut child = server.spawn(Command::new("cat")).unwrap();
+        let pid = child.id() as libc::pid_t;
+        assert_eq!(server.session_id().unwrap(), pid);
+        assert_eq!(server.foreground_pgrp().unwrap(), pid);
+        // The server is not in the session of the TTY
+        let err = server.set_foreground_pgrp(pid).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn server_builder() {
+        let mut server = TtyServer::builder()
+            .echo(false)
+            .canonical(true)
+            .erase(0x08)
+            .kill(0x15)
+            .baud(termios::B9600)
+            .winsize(WinSize::new(42, 132))
+            .slave_mode(0o600)
+            .build()
+            .unwrap();
+        let slave = server.take_slave().unwrap();
+        let t = Termios::from_fd(slave.as_raw_fd()).unwrap();
+        assert_eq!(t.c_lflag & termios::ECHO, 0);
+        assert!(t.c_lflag & termios::ICANON != 0);
+        assert_eq!((t.c_cc[termios::VERASE], t.c_cc[termios::VKILL]), (0x08, 0x15));
+        assert_eq!(termios::cfgetospeed(&t), termios::B9600);
+        assert_eq!(get_winsize(&slave).unwrap(), WinSize::new(42, 132));
+        assert_eq!(fs::metadata(server.as_ref()).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let mut server = TtyServer::builder().keep_slave(false).nonblocking(true).cloexec(false)
+            .build().unwrap();
+        assert!(server.take_slave().is_none());
+        let fd = server.as_raw_fd();
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_NONBLOCK != 0);
+        assert_eq!(unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC, 0);
+    }
+}
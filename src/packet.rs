@@ -0,0 +1,130 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use libc;
+use std::cmp;
+use std::io::{self, Read};
+
+pub use ffi::PacketControl;
+
+// Default read size, a bigger slave output being split into several data packets
+const PACKET_BUFFER_SIZE: usize = 4096;
+
+// From asm-generic/ioctls.h
+const TIOCPKT_DATA: u8 = 0;
+
+/// A read from a master TTY in packet mode
+#[derive(Debug, PartialEq, Eq)]
+pub enum Packet<'a> {
+    /// Output of the slave
+    Data(&'a [u8]),
+    /// Change of the slave output state (e.g. flush or Ctrl-S/Ctrl-Q)
+    Control(PacketControl),
+}
+
+/// Split each read from a master TTY in packet mode into a control byte and data
+pub struct PacketReader<T> where T: Read {
+    inner: T,
+    buf: Vec<u8>,
+}
+
+impl<T> PacketReader<T> where T: Read {
+    /// Wrap a master TTY which must already be in packet mode (cf. `ffi::set_packet_mode()`)
+    pub fn new(inner: T) -> PacketReader<T> {
+        PacketReader::with_capacity(inner, PACKET_BUFFER_SIZE)
+    }
+
+    /// Same as `new()` but read up to `capacity` bytes at once, including the control byte
+    pub fn with_capacity(inner: T, capacity: usize) -> PacketReader<T> {
+        PacketReader {
+            inner: inner,
+            // Room for the control byte and at least one byte of data
+            buf: vec![0; cmp::max(capacity, 2)],
+        }
+    }
+
+    /// Read the next packet, or `None` if all the slave file descriptors are closed
+    pub fn read_packet<'a>(&'a mut self) -> io::Result<Option<Packet<'a>>> {
+        let len = loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                // Reading a master TTY fails with EIO on slave hangup
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        };
+        if len == 0 {
+            return Ok(None);
+        }
+        match self.buf[0] {
+            TIOCPKT_DATA => Ok(Some(Packet::Data(&self.buf[1..len]))),
+            bits => Ok(Some(Packet::Control(PacketControl::from_bits(bits)))),
+        }
+    }
+
+    /// Get back the master TTY
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::collections::VecDeque;
+    use std::io::{self, Read};
+    use super::{Packet, PacketReader};
+
+    // Return one chunk per read, like a master TTY in packet mode, then hang up
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(chunk) => {
+                    let len = chunk.len().min(buf.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    Ok(len)
+                },
+                None => Err(io::Error::from_raw_os_error(libc::EIO)),
+            }
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let chunks = vec![&b"\0foo"[..], b"\x01", b"\x06", b"\x08", b"\x10", b"\x20", b"\0bar"];
+        let mut reader = PacketReader::new(Chunks(chunks.into_iter().collect()));
+        assert_eq!(reader.read_packet().unwrap(), Some(Packet::Data(b"foo")));
+        let mut controls = Vec::new();
+        for _ in 0..5 {
+            match reader.read_packet().unwrap() {
+                Some(Packet::Control(c)) => controls.push(c),
+                p => panic!("Unexpected packet: {:?}", p),
+            }
+        }
+        assert!(controls[0].flush_read() && !controls[0].flush_write());
+        assert!(controls[1].flush_write() && controls[1].stop() && !controls[1].start());
+        assert!(controls[2].start() && !controls[2].stop());
+        assert!(controls[3].no_stop() && !controls[3].do_stop());
+        assert!(controls[4].do_stop() && !controls[4].no_stop());
+        assert_eq!(reader.read_packet().unwrap(), Some(Packet::Data(b"bar")));
+        assert_eq!(reader.read_packet().unwrap(), None);
+
+        // A read is limited to the capacity
+        let mut reader = PacketReader::with_capacity(Chunks(vec![&b"\0foo"[..]].into_iter().collect()), 3);
+        assert_eq!(reader.read_packet().unwrap(), Some(Packet::Data(b"fo")));
+    }
+}
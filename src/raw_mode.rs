@@ -0,0 +1,172 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::Arc;
+use termios::{self, Termios, cfmakeraw};
+
+#[derive(Clone)]
+enum Kind {
+    Default,
+    Raw,
+    Cbreak,
+    Custom(Arc<dyn Fn(&mut Termios) + Send + Sync>),
+}
+
+/// Configuration of a peer TTY bound to a `TtyClient`
+///
+/// The signal keys (e.g. Ctrl-C) are sent as bytes to the master TTY unless the signals are kept,
+/// in which case they are handled by the peer TTY for the local process group.
+#[derive(Clone)]
+pub struct RawMode {
+    kind: Kind,
+    keep_signals: bool,
+    keep_output_processing: bool,
+}
+
+impl RawMode {
+    fn new(kind: Kind) -> RawMode {
+        RawMode {
+            kind: kind,
+            keep_signals: false,
+            keep_output_processing: false,
+        }
+    }
+
+    /// Full raw mode, as set by `cfmakeraw(3)`
+    pub fn raw() -> RawMode {
+        RawMode::new(Kind::Raw)
+    }
+
+    /// No echo nor line editing, but the signals and the output processing are kept
+    pub fn cbreak() -> RawMode {
+        RawMode::new(Kind::Cbreak)
+    }
+
+    /// Configure the peer TTY with `setup`, given its original configuration
+    pub fn custom<F>(setup: F) -> RawMode where F: Fn(&mut Termios) + Send + Sync + 'static {
+        RawMode::new(Kind::Custom(Arc::new(setup)))
+    }
+
+    /// Keep the signal keys (i.e. `ISIG`) of the original configuration
+    pub fn keep_signals(mut self, keep: bool) -> RawMode {
+        self.keep_signals = keep;
+        self
+    }
+
+    /// Keep the output processing (i.e. `OPOST`) of the original configuration
+    pub fn keep_output_processing(mut self, keep: bool) -> RawMode {
+        self.keep_output_processing = keep;
+        self
+    }
+
+    /// Get the configuration derived from the `orig` one
+    pub fn apply(&self, orig: &Termios) -> Termios {
+        let mut termios = *orig;
+        match self.kind {
+            Kind::Default => {
+                termios.c_lflag &= !(termios::ECHO | termios::ICANON | termios::ISIG);
+                termios.c_iflag &= !(termios::IGNBRK | termios::ICRNL);
+                termios.c_iflag |= termios::BRKINT;
+            },
+            Kind::Raw => cfmakeraw(&mut termios),
+            Kind::Cbreak => termios.c_lflag &= !(termios::ECHO | termios::ICANON),
+            Kind::Custom(ref setup) => {
+                setup(&mut termios);
+                return self.keep(orig, termios);
+            },
+        }
+        termios.c_cc[termios::VMIN] = 1;
+        termios.c_cc[termios::VTIME] = 0;
+        self.keep(orig, termios)
+    }
+
+    fn keep(&self, orig: &Termios, mut termios: Termios) -> Termios {
+        if self.keep_signals {
+            termios.c_lflag = (termios.c_lflag & !termios::ISIG) | (orig.c_lflag & termios::ISIG);
+        }
+        if self.keep_output_processing {
+            termios.c_oflag = (termios.c_oflag & !termios::OPOST) | (orig.c_oflag & termios::OPOST);
+        }
+        termios
+    }
+}
+
+impl Default for RawMode {
+    /// No echo, line editing nor signals, but the output processing is kept
+    fn default() -> RawMode {
+        RawMode::new(Kind::Default)
+    }
+}
+
+impl fmt::Debug for RawMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Default => "Default",
+            Kind::Raw => "Raw",
+            Kind::Cbreak => "Cbreak",
+            Kind::Custom(..) => "Custom",
+        };
+        f.debug_struct("RawMode")
+            .field("kind", &kind)
+            .field("keep_signals", &self.keep_signals)
+            .field("keep_output_processing", &self.keep_output_processing)
+            .finish()
+    }
+}
+
+/// When and how a `TtyClient` restores the original configuration of its input peer TTY
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RestoreTermios {
+    /// When dropped, discarding the pending input (i.e. `TCSAFLUSH`)
+    #[default]
+    Flush,
+    /// When dropped, once the pending output is written (i.e. `TCSADRAIN`)
+    Drain,
+    /// When dropped, immediately (i.e. `TCSANOW`)
+    Now,
+    /// Never, e.g. to hand over the configured peer
+    Never,
+}
+
+#[cfg(test)]
+mod tests {
+    use ffi::openpty;
+    use std::os::unix::io::AsRawFd;
+    use super::RawMode;
+    use termios::{self, Termios};
+
+    #[test]
+    fn raw_modes() {
+        let pty = openpty(None, None).unwrap();
+        let orig = Termios::from_fd(pty.slave.as_raw_fd()).unwrap();
+        assert!(orig.c_lflag & termios::ISIG != 0 && orig.c_oflag & termios::OPOST != 0);
+
+        let raw = RawMode::raw().apply(&orig);
+        assert_eq!(raw.c_lflag & (termios::ISIG | termios::ICANON | termios::ECHO), 0);
+        assert_eq!(raw.c_oflag & termios::OPOST, 0);
+
+        let raw = RawMode::raw().keep_signals(true).keep_output_processing(true).apply(&orig);
+        assert!(raw.c_lflag & termios::ISIG != 0 && raw.c_oflag & termios::OPOST != 0);
+        assert_eq!(raw.c_lflag & termios::ICANON, 0);
+
+        let cbreak = RawMode::cbreak().apply(&orig);
+        assert!(cbreak.c_lflag & termios::ISIG != 0);
+        assert_eq!(cbreak.c_lflag & (termios::ICANON | termios::ECHO), 0);
+
+        let custom = RawMode::custom(|t| t.c_lflag &= !termios::ECHO).apply(&orig);
+        assert!(custom.c_lflag & termios::ICANON != 0);
+        assert_eq!(custom.c_lflag & termios::ECHO, 0);
+    }
+}
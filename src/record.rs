@@ -0,0 +1,322 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Session recording of the data going through a `TtyClient`
+
+use ffi::WinSize;
+use libc;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::mem;
+use std::str;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Receive a copy of the data going through a `TtyClient`
+///
+/// Recording failures are ignored by the `TtyClient`.
+pub trait Recorder: Send {
+    /// Begin the recording, with the initial window size of the peer
+    fn start(&mut self, winsize: &WinSize) -> io::Result<()>;
+
+    /// Record the output of the master TTY
+    fn output(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Record the input of the peer, if requested
+    fn input(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Record a resize of the peer
+    fn resize(&mut self, _winsize: &WinSize) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Decode the longest valid UTF-8 prefix, keeping an incomplete trailing sequence in `pending`
+fn decode_utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let mut out = String::with_capacity(pending.len());
+    let mut start = 0;
+    loop {
+        match str::from_utf8(&pending[start..]) {
+            Ok(s) => {
+                out.push_str(s);
+                start = pending.len();
+                break;
+            },
+            Err(e) => {
+                let valid = start + e.valid_up_to();
+                out.push_str(unsafe { str::from_utf8_unchecked(&pending[start..valid]) });
+                match e.error_len() {
+                    Some(len) => {
+                        out.push('\u{FFFD}');
+                        start = valid + len;
+                    },
+                    // Incomplete sequence, wait for the next data
+                    None => {
+                        start = valid;
+                        break;
+                    },
+                }
+            },
+        }
+    }
+    pending.drain(..start);
+    out
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Recorder writing an asciicast v2 file, as used by asciinema
+///
+/// Each line of `writer` is a JSON document: the header then the timestamped output (`o`),
+/// input (`i`) and resize (`r`) events.
+pub struct Asciicast<W> where W: Write + Send {
+    writer: W,
+    start: Option<Instant>,
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+impl<W> Asciicast<W> where W: Write + Send {
+    pub fn new(writer: W) -> Asciicast<W> {
+        Asciicast {
+            writer: writer,
+            start: None,
+            pending_output: Vec::new(),
+            pending_input: Vec::new(),
+        }
+    }
+
+    /// Get back the writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn event(&mut self, kind: &str, data: &str) -> io::Result<()> {
+        let elapsed = match self.start {
+            Some(s) => s.elapsed(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Recording not started")),
+        };
+        let mut line = format!("[{}.{:06}, \"{}\", ", elapsed.as_secs(), elapsed.subsec_micros(), kind);
+        push_json_str(&mut line, data);
+        line.push_str("]\n");
+        try!(self.writer.write_all(line.as_bytes()));
+        self.writer.flush()
+    }
+}
+
+impl<W> Recorder for Asciicast<W> where W: Write + Send {
+    fn start(&mut self, winsize: &WinSize) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let header = format!("{{\"version\": 2, \"width\": {}, \"height\": {}, \"timestamp\": {}}}\n",
+                             winsize.cols(), winsize.rows(), timestamp);
+        try!(self.writer.write_all(header.as_bytes()));
+        try!(self.writer.flush());
+        self.start = Some(Instant::now());
+        Ok(())
+    }
+
+    fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        let text = decode_utf8(&mut self.pending_output, data);
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.event("o", &text)
+    }
+
+    fn input(&mut self, data: &[u8]) -> io::Result<()> {
+        let text = decode_utf8(&mut self.pending_input, data);
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.event("i", &text)
+    }
+
+    fn resize(&mut self, winsize: &WinSize) -> io::Result<()> {
+        let size = format!("{}x{}", winsize.cols(), winsize.rows());
+        self.event("r", &size)
+    }
+}
+
+fn now_since_epoch() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+// Local date and time, e.g. "2016-03-01 12:34:56"
+fn local_date() -> String {
+    let now = now_since_epoch().as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return String::new();
+    }
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday,
+            tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Recorder writing a `script(1)` typescript and its timing file, as read by `scriptreplay(1)`
+///
+/// The timing file uses the classic format: one "delay size" line per output chunk, the delay
+/// being the number of seconds since the previous chunk.
+pub struct Typescript<W, T> where W: Write + Send, T: Write + Send {
+    script: W,
+    timing: T,
+    last: Option<Instant>,
+}
+
+impl<W, T> Typescript<W, T> where W: Write + Send, T: Write + Send {
+    pub fn new(script: W, timing: T) -> Typescript<W, T> {
+        Typescript {
+            script: script,
+            timing: timing,
+            last: None,
+        }
+    }
+
+    /// Get back the typescript and timing writers
+    pub fn into_inner(self) -> (W, T) {
+        (self.script, self.timing)
+    }
+}
+
+impl<W, T> Recorder for Typescript<W, T> where W: Write + Send, T: Write + Send {
+    fn start(&mut self, winsize: &WinSize) -> io::Result<()> {
+        // The first line is skipped by scriptreplay(1)
+        let header = format!("Script started on {} [COLUMNS=\"{}\" LINES=\"{}\"]\n", local_date(),
+                             winsize.cols(), winsize.rows());
+        try!(self.script.write_all(header.as_bytes()));
+        try!(self.script.flush());
+        self.last = Some(Instant::now());
+        Ok(())
+    }
+
+    fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        let now = Instant::now();
+        let delay = match self.last {
+            Some(last) => now - last,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Recording not started")),
+        };
+        self.last = Some(now);
+        try!(self.script.write_all(data));
+        try!(self.script.flush());
+        try!(write!(self.timing, "{}.{:06} {}\n", delay.as_secs(), delay.subsec_micros(), data.len()));
+        self.timing.flush()
+    }
+}
+
+/// Recorder writing `ttyrec` frames
+///
+/// Each frame is a header of three little-endian 32-bit integers (seconds and microseconds
+/// since the epoch, data size) followed by the output data.
+pub struct Ttyrec<W> where W: Write + Send {
+    writer: W,
+}
+
+impl<W> Ttyrec<W> where W: Write + Send {
+    pub fn new(writer: W) -> Ttyrec<W> {
+        Ttyrec {
+            writer: writer,
+        }
+    }
+
+    /// Get back the writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> Recorder for Ttyrec<W> where W: Write + Send {
+    fn start(&mut self, _winsize: &WinSize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        let now = now_since_epoch();
+        let mut header = [0; 12];
+        header[0..4].copy_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&now.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        try!(self.writer.write_all(&header));
+        try!(self.writer.write_all(data));
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Asciicast, Recorder, Ttyrec, Typescript, decode_utf8};
+    use ffi::WinSize;
+
+    #[test]
+    fn utf8_split() {
+        let mut pending = Vec::new();
+        assert_eq!(decode_utf8(&mut pending, b"a\xc3"), "a");
+        assert_eq!(decode_utf8(&mut pending, b"\xa9\xffb"), "\u{e9}\u{FFFD}b");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn asciicast_events() {
+        let mut cast = Asciicast::new(Vec::new());
+        cast.start(&WinSize::new(24, 80)).unwrap();
+        cast.output(b"\"hi\"\r\n\x1b[0m").unwrap();
+        cast.resize(&WinSize::new(40, 100)).unwrap();
+        let out = String::from_utf8(cast.into_inner()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("{\"version\": 2, \"width\": 80, \"height\": 24, \"timestamp\": "));
+        assert!(lines[1].ends_with(", \"o\", \"\\\"hi\\\"\\r\\n\\u001b[0m\"]"));
+        assert!(lines[2].ends_with(", \"r\", \"100x40\"]"));
+    }
+
+    #[test]
+    fn typescript_timing() {
+        let mut script = Typescript::new(Vec::new(), Vec::new());
+        script.start(&WinSize::new(24, 80)).unwrap();
+        script.output(b"foo").unwrap();
+        script.output(b"\r\n").unwrap();
+        let (script, timing) = script.into_inner();
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.starts_with("Script started on "));
+        assert!(script.ends_with(" [COLUMNS=\"80\" LINES=\"24\"]\nfoo\r\n"));
+        let timing = String::from_utf8(timing).unwrap();
+        let sizes: Vec<&str> = timing.lines().map(|l| l.split(' ').nth(1).unwrap()).collect();
+        assert_eq!(sizes, vec!["3", "2"]);
+    }
+
+    #[test]
+    fn ttyrec_frame() {
+        let mut rec = Ttyrec::new(Vec::new());
+        rec.start(&WinSize::new(24, 80)).unwrap();
+        rec.output(b"foo").unwrap();
+        let out = rec.into_inner();
+        assert_eq!(out.len(), 15);
+        assert_eq!(&out[8..], b"\x03\0\0\0foo");
+    }
+}
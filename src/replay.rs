@@ -0,0 +1,308 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Replay of sessions recorded with `record::Typescript` or `record::Ttyrec`
+
+use std::io::{self, BufRead, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+/// Chunk of recorded output
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Time elapsed since the previous frame
+    pub delay: Duration,
+    pub data: Vec<u8>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Same as `Duration::try_from_secs_f64()`, which requires Rust 1.66
+fn secs_to_duration(secs: f64) -> Option<Duration> {
+    // `u64::MAX as f64` is rounded up to 2^64, which is out of range
+    if secs >= 0.0 && secs < u64::MAX as f64 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+// Divide `delay` by `speed`, saturating to `Duration::MAX` instead of overflowing
+fn scale_delay(delay: Duration, speed: f64) -> Duration {
+    if speed.is_finite() {
+        secs_to_duration(delay.as_secs_f64() / speed).unwrap_or(Duration::MAX)
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+// Fill `buf` or return false on end of file before the first byte
+fn read_frame_part<R>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> where R: Read {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match reader.read(&mut buf[pos..]) {
+            Ok(0) if pos == 0 => return Ok(false),
+            Ok(0) => return Err(invalid_data("Truncated frame")),
+            Ok(n) => pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Frames of a `ttyrec` file
+pub struct TtyrecFrames<R> where R: Read {
+    reader: R,
+    last: Option<Duration>,
+}
+
+impl<R> TtyrecFrames<R> where R: Read {
+    pub fn new(reader: R) -> TtyrecFrames<R> {
+        TtyrecFrames {
+            reader: reader,
+            last: None,
+        }
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut header = [0; 12];
+        if !try!(read_frame_part(&mut self.reader, &mut header)) {
+            return Ok(None);
+        }
+        let le32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let usec = le32(&header[4..8]);
+        if usec >= 1_000_000 {
+            return Err(invalid_data("Invalid frame timestamp"));
+        }
+        let time = Duration::new(le32(&header[0..4]) as u64, usec * 1_000);
+        let mut data = vec![0; le32(&header[8..12]) as usize];
+        if !data.is_empty() && !try!(read_frame_part(&mut self.reader, &mut data)) {
+            return Err(invalid_data("Truncated frame"));
+        }
+        // The first frame is shown without delay, as well as any backward time change
+        let delay = match self.last {
+            Some(last) if time > last => time - last,
+            _ => Duration::from_secs(0),
+        };
+        self.last = Some(time);
+        Ok(Some(Frame {
+            delay: delay,
+            data: data,
+        }))
+    }
+}
+
+impl<R> Iterator for TtyrecFrames<R> where R: Read {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<io::Result<Frame>> {
+        match self.next_frame() {
+            Ok(Some(f)) => Some(Ok(f)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Frames of a `script(1)` typescript with its classic timing file
+pub struct TypescriptFrames<R, T> where R: Read, T: BufRead {
+    script: R,
+    timing: T,
+    header_skipped: bool,
+}
+
+impl<R, T> TypescriptFrames<R, T> where R: Read, T: BufRead {
+    pub fn new(script: R, timing: T) -> TypescriptFrames<R, T> {
+        TypescriptFrames {
+            script: script,
+            timing: timing,
+            header_skipped: false,
+        }
+    }
+
+    // The first line of the typescript is not part of the session
+    fn skip_header(&mut self) -> io::Result<()> {
+        let mut byte = [0];
+        loop {
+            if !try!(read_frame_part(&mut self.script, &mut byte)) || byte[0] == b'\n' {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if !self.header_skipped {
+            try!(self.skip_header());
+            self.header_skipped = true;
+        }
+        let mut line = String::new();
+        if try!(self.timing.read_line(&mut line)) == 0 || line.trim().is_empty() {
+            return Ok(None);
+        }
+        let mut fields = line.split_whitespace();
+        let delay = fields.next().and_then(|d| d.parse::<f64>().ok()).and_then(secs_to_duration);
+        let size = fields.next().and_then(|s| s.parse::<usize>().ok());
+        let (delay, size) = match (delay, size) {
+            (Some(d), Some(s)) => (d, s),
+            _ => return Err(invalid_data("Invalid timing line")),
+        };
+        let mut data = vec![0; size];
+        if size != 0 && !try!(read_frame_part(&mut self.script, &mut data)) {
+            return Err(invalid_data("Truncated typescript"));
+        }
+        Ok(Some(Frame {
+            delay: delay,
+            data: data,
+        }))
+    }
+}
+
+impl<R, T> Iterator for TypescriptFrames<R, T> where R: Read, T: BufRead {
+    type Item = io::Result<Frame>;
+
+    fn next(&mut self) -> Option<io::Result<Frame>> {
+        match self.next_frame() {
+            Ok(Some(f)) => Some(Ok(f)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Write recorded frames with their original timing, possibly sped up or slowed down
+pub struct Player<I> where I: Iterator<Item = io::Result<Frame>> {
+    frames: I,
+    speed: f64,
+}
+
+impl<R> Player<TtyrecFrames<R>> where R: Read {
+    /// Replay a `ttyrec` file
+    pub fn ttyrec(reader: R) -> Player<TtyrecFrames<R>> {
+        Player::new(TtyrecFrames::new(reader))
+    }
+}
+
+impl<R, T> Player<TypescriptFrames<R, T>> where R: Read, T: BufRead {
+    /// Replay a `script(1)` typescript with its timing file
+    pub fn typescript(script: R, timing: T) -> Player<TypescriptFrames<R, T>> {
+        Player::new(TypescriptFrames::new(script, timing))
+    }
+}
+
+impl<I> Player<I> where I: Iterator<Item = io::Result<Frame>> {
+    pub fn new(frames: I) -> Player<I> {
+        Player {
+            frames: frames,
+            speed: 1.0,
+        }
+    }
+
+    /// Divide the delays by `factor` (e.g. 2.0 to replay twice as fast)
+    ///
+    /// An infinite factor writes all the frames without delay.
+    pub fn set_speed(&mut self, factor: f64) -> io::Result<()> {
+        if factor.is_nan() || factor <= 0.0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid speed factor"));
+        }
+        self.speed = factor;
+        Ok(())
+    }
+
+    /// Write the next frame to `out` after its delay, or return false if there is no more frame
+    pub fn play_frame<W>(&mut self, out: &mut W) -> io::Result<bool> where W: Write {
+        let frame = match self.frames.next() {
+            Some(f) => try!(f),
+            None => return Ok(false),
+        };
+        let delay = scale_delay(frame.delay, self.speed);
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+        try!(out.write_all(&frame.data));
+        try!(out.flush());
+        Ok(true)
+    }
+
+    /// Write all the remaining frames to `out`
+    pub fn play<W>(&mut self, out: &mut W) -> io::Result<()> where W: Write {
+        while try!(self.play_frame(out)) {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffi::WinSize;
+    use record::{Recorder, Ttyrec, Typescript};
+    use std::f64;
+    use std::io;
+    use std::time::Duration;
+    use super::{Frame, Player, TypescriptFrames, scale_delay};
+
+    #[test]
+    fn typescript_frames() {
+        let script = &b"Script started on 2016-03-01 12:34:56\nfoo\r\nbar"[..];
+        let timing = &b"0.500000 5\n1.250000 3\n"[..];
+        let frames: Vec<Frame> = TypescriptFrames::new(script, timing).map(|f| f.unwrap()).collect();
+        assert_eq!(frames, vec![
+            Frame { delay: Duration::from_millis(500), data: b"foo\r\n".to_vec() },
+            Frame { delay: Duration::from_millis(1250), data: b"bar".to_vec() },
+        ]);
+    }
+
+    #[test]
+    fn invalid_timings() {
+        for timing in [&b"1e30 3\n"[..], b"-1 3\n", b"inf 3\n", b"NaN 3\n", b"0.5\n"].iter() {
+            let mut frames = TypescriptFrames::new(&b"header\nfoo"[..], *timing);
+            let err = frames.next().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn scaled_delays() {
+        let second = Duration::from_secs(1);
+        assert_eq!(scale_delay(second, 2.0), Duration::from_millis(500));
+        assert_eq!(scale_delay(second, f64::INFINITY), Duration::from_secs(0));
+        assert_eq!(scale_delay(second, 1e-300), Duration::MAX);
+        assert_eq!(scale_delay(Duration::from_secs(0), 1e-300), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn replay_recordings() {
+        let ws = WinSize::new(24, 80);
+        let mut rec = Ttyrec::new(Vec::new());
+        rec.start(&ws).unwrap();
+        rec.output(b"foo").unwrap();
+        rec.output(b"bar").unwrap();
+        let mut out = Vec::new();
+        Player::ttyrec(&rec.into_inner()[..]).play(&mut out).unwrap();
+        assert_eq!(out, b"foobar");
+
+        let mut rec = Typescript::new(Vec::new(), Vec::new());
+        rec.start(&ws).unwrap();
+        rec.output(b"foo").unwrap();
+        rec.output(b"bar").unwrap();
+        let (script, timing) = rec.into_inner();
+        let mut player = Player::typescript(&script[..], &timing[..]);
+        player.set_speed(f64::INFINITY).unwrap();
+        assert!(player.set_speed(0.0).is_err());
+        let mut out = Vec::new();
+        player.play(&mut out).unwrap();
+        assert_eq!(out, b"foobar");
+    }
+}
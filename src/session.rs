@@ -0,0 +1,605 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Detachable sessions, like `dtach(1)`
+//!
+//! A `Session` exposes a TTY on a Unix socket until its process exits. A client can `attach()`
+//! to it from its own terminal, detach with an escape key and attach again later. The output of
+//! the TTY is discarded while no client is attached.
+
+use copy::{Stopper, write_all};
+use ffi::{WinSize, get_winsize, read_master, set_winsize};
+use libc;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, File, Permissions};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, SyncSender, channel, sync_channel};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use termios::{self, Termios, tcsetattr};
+use winch::on_resize;
+use {Error, FileDesc, Result, TtyServer, RawMode, lock};
+
+/// Detach key used by `dtach(1)`, i.e. Ctrl-\
+pub const DEFAULT_DETACH_KEY: u8 = 0x1c;
+
+const BUFFER_SIZE: usize = 4096;
+const MAX_MESSAGE_SIZE: usize = 1 << 20;
+// Number of messages queued for a client
+const CLIENT_QUEUE_SIZE: usize = 16;
+// Time given to a client to receive the end of the output once the session ended
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MSG_DATA: u8 = 0;
+const MSG_RESIZE: u8 = 1;
+const MSG_DETACH: u8 = 2;
+const MSG_EXIT: u8 = 3;
+
+// Framed message: type (1 byte), payload size (big-endian u32), payload
+#[derive(Debug, PartialEq, Eq)]
+enum Message {
+    Data(Vec<u8>),
+    Resize(WinSize),
+    Detach,
+    Exit(i32),
+}
+
+impl Message {
+    fn write_to<W>(&self, writer: &mut W) -> io::Result<()> where W: Write {
+        writer.write_all(&self.to_bytes())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; 5];
+        buf[0] = match *self {
+            Message::Data(ref data) => {
+                buf.extend_from_slice(data);
+                MSG_DATA
+            },
+            Message::Resize(ref ws) => {
+                for dim in [ws.rows(), ws.cols(), ws.xpixel(), ws.ypixel()].iter() {
+                    buf.extend_from_slice(&dim.to_be_bytes());
+                }
+                MSG_RESIZE
+            },
+            Message::Detach => MSG_DETACH,
+            Message::Exit(code) => {
+                buf.extend_from_slice(&code.to_be_bytes());
+                MSG_EXIT
+            },
+        };
+        let len = (buf.len() - 5) as u32;
+        buf[1..5].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    // Return None on end of file
+    fn read_from<R>(reader: &mut R) -> io::Result<Option<Message>> where R: Read {
+        let mut header = [0; 5];
+        loop {
+            match reader.read(&mut header[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(..) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        try!(reader.read_exact(&mut header[1..]));
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Session message too big"));
+        }
+        let mut payload = vec![0; len];
+        try!(reader.read_exact(&mut payload));
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid session message");
+        let msg = match (header[0], len) {
+            (MSG_DATA, _) => Message::Data(payload),
+            (MSG_RESIZE, 8) => {
+                let dim = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+                Message::Resize(WinSize::with_pixels(dim(0), dim(2), dim(4), dim(6)))
+            },
+            (MSG_DETACH, 0) => Message::Detach,
+            (MSG_EXIT, 4) => Message::Exit(i32::from_be_bytes([payload[0], payload[1], payload[2],
+                                                               payload[3]])),
+            _ => return Err(invalid()),
+        };
+        Ok(Some(msg))
+    }
+}
+
+// Attached client, whose messages are written by a dedicated thread so that a client which
+// doesn't read (e.g. suspended) only stalls the session output until another one attaches
+struct Client {
+    id: usize,
+    queue: SyncSender<Message>,
+    stopper: Arc<Stopper>,
+    writer: JoinHandle<()>,
+    // Disconnected once the writer thread is done
+    done: Receiver<()>,
+}
+
+impl Client {
+    fn new(id: usize, stream: UnixStream) -> io::Result<Client> {
+        let stopper = Arc::new(try!(Stopper::new()));
+        let (queue_tx, queue_rx) = sync_channel(CLIENT_QUEUE_SIZE);
+        let (done_tx, done_rx) = channel();
+        let writer_stopper = stopper.clone();
+        let writer = thread::spawn(move || {
+            output_loop(stream, queue_rx, writer_stopper);
+            drop(done_tx);
+        });
+        Ok(Client {
+            id: id,
+            queue: queue_tx,
+            stopper: stopper,
+            writer: writer,
+            done: done_rx,
+        })
+    }
+
+    // Send `msg` after the queued output, or disconnect the client right away if it is stalled
+    fn close(self, msg: Option<Message>) {
+        let sent = match msg {
+            Some(m) => self.queue.try_send(m).is_ok(),
+            None => true,
+        };
+        if !sent {
+            self.stopper.stop();
+        }
+    }
+}
+
+fn output_loop(stream: UnixStream, queue: Receiver<Message>, stopper: Arc<Stopper>) {
+    for msg in queue.iter() {
+        if stopper.write_all(stream.as_raw_fd(), &msg.to_bytes()).is_err() {
+            break;
+        }
+    }
+    // Also end the input of the client
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+type SharedClient = Arc<Mutex<Option<Client>>>;
+
+/// TTY exposed on a Unix socket, with at most one attached client at a time
+pub struct Session {
+    server: TtyServer,
+    child: Option<Child>,
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Session {
+    /// Expose the TTY of `server` on a new Unix socket at `path`, only accessible by its owner
+    ///
+    /// The session ends when all the slave file descriptors are closed, so the slave of `server`
+    /// must already be taken (cf. `TtyServer::take_slave()`).
+    pub fn new<P>(server: TtyServer, path: P) -> Result<Session> where P: AsRef<Path> {
+        let path = path.as_ref().to_path_buf();
+        let listener = match bind_private(&path) {
+            Ok(l) => l,
+            Err(e) => return Err(Error::Socket { path: path, cause: e }),
+        };
+        Ok(Session {
+            server: server,
+            child: None,
+            listener: listener,
+            path: path,
+        })
+    }
+
+    /// Spawn a new process connected to the slave TTY (cf. `TtyServer::spawn()`) and expose it
+    pub fn spawn<P>(server: TtyServer, cmd: Command, path: P) -> Result<Session>
+            where P: AsRef<Path> {
+        let mut session = try!(Session::new(server, path));
+        session.child = Some(try!(session.server.spawn(cmd)));
+        Ok(session)
+    }
+
+    /// Get the socket path
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+
+    /// Get the TTY server
+    pub fn server(&self) -> &TtyServer {
+        &self.server
+    }
+
+    /// Serve the clients until the end of the session, then wait for the spawned process if any
+    ///
+    /// A new client takes the session from the attached one, if any. The session output waits
+    /// for the attached client, unless another one takes over.
+    pub fn serve(mut self) -> Result<Option<ExitStatus>> {
+        let current: SharedClient = Arc::new(Mutex::new(None));
+        let listener = try!(self.listener.try_clone());
+        let master = try!(self.server.get_master().try_clone());
+        let accept_current = current.clone();
+        let acceptor = thread::spawn(move || accept_loop(listener, master, accept_current));
+
+        let mut buf = [0; BUFFER_SIZE];
+        loop {
+            let len = match self.server.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let mut msg = Message::Data(buf[..len].to_vec());
+            // Don't hold the lock while waiting for the client, which can then be replaced
+            loop {
+                let (id, queue) = match *lock(&current) {
+                    Some(ref c) => (c.id, c.queue.clone()),
+                    None => break,
+                };
+                match queue.send(msg) {
+                    Ok(()) => break,
+                    Err(e) => {
+                        // The client is gone, try the new one if any
+                        msg = e.0;
+                        let mut client = lock(&current);
+                        if client.as_ref().map(|c| c.id) == Some(id) {
+                            *client = None;
+                        }
+                    },
+                }
+            }
+        }
+
+        // Wake up the acceptor thread
+        unsafe { libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR) };
+        let _ = acceptor.join();
+        let status = match self.child {
+            Some(ref mut c) => Some(try!(c.wait().map_err(Error::Io))),
+            None => None,
+        };
+        let client = lock(&current).take();
+        if let Some(c) = client {
+            if let Some(s) = status {
+                let code = s.code().unwrap_or_else(|| 128 + s.signal().unwrap_or(0));
+                let _ = c.queue.try_send(Message::Exit(code));
+            }
+            let Client { queue, stopper, writer, done, .. } = c;
+            drop(queue);
+            let _ = done.recv_timeout(EXIT_TIMEOUT);
+            stopper.stop();
+            let _ = writer.join();
+        }
+        Ok(status)
+    }
+}
+
+impl Drop for Session {
+    /// Remove the socket
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Bind the socket in a new private directory, then link it to `path` once only accessible by its
+// owner, so that no other user can connect in between
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let name = match path.file_name() {
+        Some(n) => n,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "No socket name")),
+    };
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", process::id()));
+    let dir = parent.join(dir_name);
+    try!(DirBuilder::new().mode(0o700).create(&dir));
+    let tmp = dir.join("socket");
+    let listener = UnixListener::bind(&tmp).and_then(|l| {
+        try!(fs::set_permissions(&tmp, Permissions::from_mode(0o600)));
+        // Unlike rename(2), fail if `path` already exists
+        try!(fs::hard_link(&tmp, path));
+        Ok(l)
+    });
+    let _ = fs::remove_file(&tmp);
+    let _ = fs::remove_dir(&dir);
+    listener
+}
+
+// Get the user ID of the process connected to `stream`
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    match unsafe { libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                                    &mut cred as *mut _ as *mut libc::c_void, &mut len) } {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn accept_loop(listener: UnixListener, master: File, current: SharedClient) {
+    let mut next_id = 0;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+            Err(_) => break,
+        };
+        // Only the owner can attach, whatever the socket permissions
+        match peer_uid(&stream) {
+            Ok(uid) if uid == unsafe { libc::geteuid() } => {},
+            _ => continue,
+        }
+        let (reader, master) = match (stream.try_clone(), master.try_clone()) {
+            (Ok(r), Ok(m)) => (r, m),
+            _ => continue,
+        };
+        let id = next_id;
+        next_id += 1;
+        let new_client = match Client::new(id, stream) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let old = lock(&current).replace(new_client);
+        if let Some(old) = old {
+            old.close(Some(Message::Detach));
+        }
+        let current = current.clone();
+        thread::spawn(move || input_loop(id, reader, master, current));
+    }
+}
+
+fn input_loop(id: usize, mut stream: UnixStream, mut master: File, current: SharedClient) {
+    loop {
+        match Message::read_from(&mut stream) {
+            Ok(Some(Message::Data(data))) => {
+                if master.write_all(&data).is_err() {
+                    break;
+                }
+            },
+            Ok(Some(Message::Resize(ws))) => {
+                let _ = set_winsize(&master, &ws);
+            },
+            // Detach, end of file or protocol error
+            _ => break,
+        }
+    }
+    let client = {
+        let mut client = lock(&current);
+        match client.as_ref().map(|c| c.id) {
+            Some(i) if i == id => client.take(),
+            _ => None,
+        }
+    };
+    if let Some(c) = client {
+        c.close(None);
+    }
+}
+
+/// How an `attach()` ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachEnd {
+    /// The detach key was typed, the session keeps running
+    Detached,
+    /// Another client attached to the session
+    Stolen,
+    /// The session process exited with this code (128 + the signal number if killed)
+    Exited(i32),
+    /// The session ended without exit code, or the connection broke
+    Disconnected,
+}
+
+// Restore the peer configuration when dropped
+struct RawGuard {
+    fd: RawFd,
+    termios_orig: Termios,
+}
+
+impl RawGuard {
+    fn new(fd: RawFd) -> Result<RawGuard> {
+        let termios_orig = try!(Termios::from_fd(fd).map_err(Error::GetTermios));
+        try!(tcsetattr(fd, termios::TCSAFLUSH, &RawMode::default().apply(&termios_orig)).map_err(Error::SetTermios));
+        Ok(RawGuard {
+            fd: fd,
+            termios_orig: termios_orig,
+        })
+    }
+}
+
+impl Drop for RawGuard {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, termios::TCSAFLUSH, &self.termios_orig);
+    }
+}
+
+/// Attach the `peer` terminal (e.g. stdin) to the session listening at `path`
+///
+/// Typing `detach_key` (e.g. `DEFAULT_DETACH_KEY`) detaches from the session. The session
+/// window size follows the peer one.
+pub fn attach<P, T>(path: P, peer: &T, detach_key: u8) -> Result<AttachEnd>
+        where P: AsRef<Path>, T: AsRawFd {
+    let path = path.as_ref();
+    let stream = match UnixStream::connect(path) {
+        Ok(s) => s,
+        Err(e) => return Err(Error::Socket { path: path.to_path_buf(), cause: e }),
+    };
+    let mut reader = try!(stream.try_clone());
+    let mut writer = stream;
+    let peer = FileDesc::new(peer.as_raw_fd(), false);
+    let _raw = try!(RawGuard::new(peer.as_raw_fd()));
+
+    if let Ok(ws) = get_winsize(&peer) {
+        try!(Message::Resize(ws).write_to(&mut writer));
+    }
+    // The resize callback only notifies the loop, which sends the new size
+    let resized = match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) } {
+        -1 => return Err(Error::Io(io::Error::last_os_error())),
+        fd => FileDesc::new(fd, true),
+    };
+    let winch_resized = FileDesc::new(resized.as_raw_fd(), false);
+    let _winch = try!(on_resize(move || {
+        let one: u64 = 1;
+        let len = mem::size_of::<u64>();
+        unsafe { libc::write(winch_resized.as_raw_fd(), &one as *const _ as *const _, len) };
+    }));
+    let mut send = |msg: Message| msg.write_to(&mut writer).is_ok();
+
+    let mut fds = [
+        libc::pollfd { fd: peer.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: reader.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: resized.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+    ];
+    let mut buf = [0; BUFFER_SIZE];
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(Error::Io(err));
+        }
+        if fds[2].revents != 0 {
+            // Reset the counter
+            let mut count = [0u8; 8];
+            unsafe { libc::read(resized.as_raw_fd(), count.as_mut_ptr() as *mut _, count.len()) };
+            if let Ok(ws) = get_winsize(&peer) {
+                if !send(Message::Resize(ws)) {
+                    return Ok(AttachEnd::Disconnected);
+                }
+            }
+        }
+        if fds[1].revents != 0 {
+            match Message::read_from(&mut reader) {
+                Ok(Some(Message::Data(data))) => try!(write_all(peer.as_raw_fd(), &data)),
+                Ok(Some(Message::Detach)) => return Ok(AttachEnd::Stolen),
+                Ok(Some(Message::Exit(code))) => return Ok(AttachEnd::Exited(code)),
+                Ok(Some(Message::Resize(..))) => {},
+                _ => return Ok(AttachEnd::Disconnected),
+            }
+        }
+        if fds[0].revents != 0 {
+            let len = match read_master(&peer, &mut buf) {
+                // The peer hung up
+                Ok(0) => 0,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            let data = &buf[..len];
+            match data.iter().position(|&b| b == detach_key) {
+                Some(i) => {
+                    if i != 0 {
+                        let _ = send(Message::Data(data[..i].to_vec()));
+                    }
+                    let _ = send(Message::Detach);
+                    return Ok(AttachEnd::Detached);
+                },
+                None if len == 0 => {
+                    let _ = send(Message::Detach);
+                    return Ok(AttachEnd::Detached);
+                },
+                None => {
+                    if !send(Message::Data(data.to_vec())) {
+                        return Ok(AttachEnd::Disconnected);
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ffi::WinSize;
+    use libc;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixStream;
+    use std::process::{self, Command};
+    use std::thread;
+    use std::time::Duration;
+    use super::{Message, Session, peer_uid};
+    use TtyServer;
+
+    #[test]
+    fn private_socket() {
+        let dir = env::temp_dir().join(format!("tty-session-test.{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("socket");
+        let session = Session::new(TtyServer::new::<TtyServer>(None).unwrap(), &path).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // Only the socket is left, and the path can't be reused
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(Session::new(TtyServer::new::<TtyServer>(None).unwrap(), &path).is_err());
+        let stream = UnixStream::connect(&path).unwrap();
+        assert_eq!(peer_uid(&stream).unwrap(), unsafe { libc::geteuid() });
+        drop(session);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn stalled_client() {
+        let dir = env::temp_dir().join(format!("tty-session-stalled.{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("socket");
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "read x && yes"]);
+        let session = Session::spawn(TtyServer::new::<TtyServer>(None).unwrap(), cmd, &path)
+            .unwrap();
+        let server = thread::spawn(move || session.serve().unwrap());
+        // Never read by this client
+        let mut stalled = UnixStream::connect(&path).unwrap();
+        Message::Data(b"go\n".to_vec()).write_to(&mut stalled).unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        match Message::read_from(&mut client).unwrap() {
+            Some(Message::Data(ref data)) if !data.is_empty() => {},
+            m => panic!("Unexpected message: {:?}", m),
+        }
+        Message::Data(b"\x03".to_vec()).write_to(&mut client).unwrap();
+        assert!(!server.join().unwrap().unwrap().success());
+        drop(stalled);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn messages() {
+        let msgs = [Message::Data(b"foo".to_vec()),
+                    Message::Resize(WinSize::with_pixels(24, 80, 640, 480)),
+                    Message::Detach, Message::Exit(130)];
+        let mut buf = Vec::new();
+        for msg in msgs.iter() {
+            msg.write_to(&mut buf).unwrap();
+        }
+        let mut reader = &buf[..];
+        for msg in msgs.iter() {
+            assert_eq!(Message::read_from(&mut reader).unwrap().as_ref(), Some(msg));
+        }
+        assert!(Message::read_from(&mut reader).unwrap().is_none());
+        assert!(Message::read_from(&mut &[0xff, 0, 0, 0, 0][..]).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (C) 2016 Mickaël Salaün
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, version 3 of the License.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Terminal resize (i.e. SIGWINCH) notifications
+//!
+//! A process-wide SIGWINCH handler writes to a self-pipe watched by a dedicated thread, which
+//! calls the registered callbacks. Unlike `signalfd(2)`, this doesn't require to block the
+//! signal in all threads, so it can be set up at any time. Any previous SIGWINCH handler is
+//! still called.
+
+use fd::Pipe;
+use libc::{self, c_int, c_void, sighandler_t, siginfo_t};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::IntoRawFd;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, AtomicUsize};
+use std::sync::atomic::Ordering::SeqCst;
+use std::thread;
+use lock;
+
+type Callback = Box<dyn Fn() + Send>;
+
+struct Registry {
+    next_id: usize,
+    callbacks: Vec<(usize, Callback)>,
+}
+
+// Write end of the self-pipe, or -1 if the handler is not installed yet
+static PIPE_WRITER: AtomicI32 = AtomicI32::new(-1);
+// Previous SIGWINCH handler and its flags
+static OLD_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static OLD_FLAGS: AtomicUsize = AtomicUsize::new(0);
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next_id: 0,
+    callbacks: Vec::new(),
+});
+
+// Only async-signal-safe calls are allowed
+extern "C" fn sigwinch_handler(signum: c_int, info: *mut siginfo_t, ctx: *mut c_void) {
+    unsafe {
+        let errno = *libc::__errno_location();
+        let fd = PIPE_WRITER.load(SeqCst);
+        if fd != -1 {
+            // A full pipe already has a pending notification
+            let _ = libc::write(fd, &1u8 as *const u8 as *const c_void, 1);
+        }
+        let old = OLD_HANDLER.load(SeqCst);
+        if old != libc::SIG_DFL && old != libc::SIG_IGN {
+            if OLD_FLAGS.load(SeqCst) as c_int & libc::SA_SIGINFO != 0 {
+                let f: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = mem::transmute(old);
+                f(signum, info, ctx);
+            } else {
+                let f: extern "C" fn(c_int) = mem::transmute(old);
+                f(signum);
+            }
+        }
+        *libc::__errno_location() = errno;
+    }
+}
+
+fn install() -> io::Result<()> {
+    let pipe = try!(Pipe::new());
+    let mut reader = pipe.reader;
+    let writer = pipe.writer.into_raw_fd();
+    let flags = unsafe { libc::fcntl(writer, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(writer, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(writer) };
+        return Err(err);
+    }
+    PIPE_WRITER.store(writer, SeqCst);
+
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        let mut old: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = sigwinch_handler as *const () as sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        // The previous handler must be known before a signal reaches the new one
+        let installed = libc::sigaction(libc::SIGWINCH, ptr::null(), &mut old) != -1 && {
+            OLD_FLAGS.store(old.sa_flags as usize, SeqCst);
+            OLD_HANDLER.store(old.sa_sigaction, SeqCst);
+            libc::sigaction(libc::SIGWINCH, &action, ptr::null_mut()) != -1
+        };
+        if !installed {
+            let err = io::Error::last_os_error();
+            PIPE_WRITER.store(-1, SeqCst);
+            libc::close(writer);
+            return Err(err);
+        }
+    }
+
+    thread::spawn(move || {
+        let mut buf = [0; 64];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(..) => {
+                    let registry = lock(&REGISTRY);
+                    for entry in registry.callbacks.iter() {
+                        (entry.1)();
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Registration of a resize callback, which is unregistered when dropped
+#[derive(Debug)]
+pub struct WinchHandle {
+    id: usize,
+}
+
+impl Drop for WinchHandle {
+    /// No callback call is pending once unregistered
+    fn drop(&mut self) {
+        let mut registry = lock(&REGISTRY);
+        let id = self.id;
+        registry.callbacks.retain(|&(i, _)| i != id);
+    }
+}
+
+/// Call `callback` from the watcher thread each time the process receives a SIGWINCH
+///
+/// The SIGWINCH handler is installed with the first registration. The callbacks are called in
+/// turn with the registrations locked, so a callback must not block (e.g. on a socket write),
+/// which would delay the other callbacks, `on_resize()` and any `WinchHandle` drop. It must not
+/// register nor unregister any callback either.
+pub fn on_resize<F>(callback: F) -> io::Result<WinchHandle> where F: Fn() + Send + 'static {
+    let mut registry = lock(&REGISTRY);
+    if PIPE_WRITER.load(SeqCst) == -1 {
+        try!(install());
+    }
+    let id = registry.next_id;
+    registry.next_id += 1;
+    registry.callbacks.push((id, Box::new(callback)));
+    Ok(WinchHandle {
+        id: id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use libc;
+    use std::sync::mpsc::{RecvTimeoutError, channel};
+    use std::time::Duration;
+    use super::on_resize;
+
+    #[test]
+    fn resize_callback() {
+        let (tx, rx) = channel();
+        let handle = on_resize(move || {
+            let _ = tx.send(());
+        }).unwrap();
+        assert_eq!(unsafe { libc::raise(libc::SIGWINCH) }, 0);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        // The callback, and then its sender, is dropped once unregistered
+        drop(handle);
+        loop {
+            match rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(()) => {},
+                Err(e) => {
+                    assert_eq!(e, RecvTimeoutError::Disconnected);
+                    break;
+                },
+            }
+        }
+    }
+}